@@ -15,45 +15,205 @@ const info = std.log.info;
 const config = @import("config");
 const semver = std.SemanticVersion.parse(config.version) catch unreachable;
 
+pub const std_options: std.Options = .{
+    .logFn = logFn,
+};
+
+/// The level of a log is colored when stderr is a terminal, unless NO_COLOR or --no-color is given.
+var log_color = false;
+/// Logs above it are dropped, on top of the level the build mode allows.
+var log_level = std.log.Level.debug;
+
+fn logFn(comptime level: std.log.Level, comptime scope: @Type(.EnumLiteral), comptime format: []const u8, args: anytype) void {
+    if (@intFromEnum(level) > @intFromEnum(log_level)) return;
+    const level_txt = comptime level.asText();
+    const prefix = if (scope == .default) ": " else "(" ++ @tagName(scope) ++ "): ";
+    const color = switch (level) {
+        .err => "\x1b[31m",
+        .warn => "\x1b[33m",
+        .info => "\x1b[32m",
+        .debug => "\x1b[90m",
+    };
+    std.debug.lockStdErr();
+    defer std.debug.unlockStdErr();
+    var bw = io.bufferedWriter(io.getStdErr().writer());
+    const writer = bw.writer();
+    nosuspend {
+        if (log_color) {
+            writer.writeAll(color ++ level_txt ++ "\x1b[0m" ++ prefix) catch return;
+        } else {
+            writer.writeAll(level_txt ++ prefix) catch return;
+        }
+        writer.print(format ++ "\n", args) catch return;
+        bw.flush() catch return;
+    }
+}
+
 const usage =
     \\Usage: abt [options] [--] [gradle command]
     \\
     \\Options:
     \\
+    \\  -C, --root                     Run as if abt was started in the given path, it should lead other options
     \\  -s, --since-commit             Only select projects changed since given commit in this repo
+    \\  --diff-target                  The changes compared with the since commit: workdir, index or head, workdir by default.
+    \\                                 workdir is committed + staged + unstaged + untracked, fits builds before committing,
+    \\                                 index is committed + staged, fits pre-commit hooks,
+    \\                                 head is committed only, fits CI after a partial checkout
+    \\  --base-branch                  Compare with the given branch like origin/develop when --since-commit isn't given
     \\  -i, --include                  Include projects under given path
     \\  -e, --regexp                   A project is selected if its name matches given pattern
     \\  -v, --invert-match             A project is NOT selected if its name matches given pattern
+    \\  -x, --exclude                  A project is NOT selected if its name is the given one, can be repeated
+    \\  --projects-file                Always select the projects named in the given file, one name or pattern a line, # starts a comment
     \\  -f, --filter                   A project is selected if the given shell command pass in its directory
     \\  -c, --settings-file            The gradle settings file will be generated and used
-    \\  --threshold                    The max number of project can run at one time, projects more than it will be sepearted into many run
-    \\  --max-depth                    Descend at most n directory levels
+    \\  --per-gradle-root              Run gradle in the nearest dir having gradlew of each project, with one settings file per such dir,
+    \\                                 projects are named relative to that dir like its build files do
+    \\  --threshold                    The max number of project can run at one time, projects more than it will be sepearted into many run,
+    \\                                 1000 by default, or the projects split evenly among the --jobs runs
+    \\  -j, --jobs                     Run at most n gradle processes at one time, each with its own batch of projects
+    \\  -t, --task                     Run the given task of each selected project like :app:lint, before the gradle command if any
+    \\  --gradle-arg                   Pass the given arg to gradle before the tasks, like --offline or -Pkey=value, can be repeated
+    \\  --timeout                      Stop gradle once it runs longer than the given seconds
+    \\  --log-file                     Copy the gradle output into the given file too. Without it gradle writes
+    \\                                 to the terminal directly, the end of its stderr is only repeated on failures
+    \\                                 when stderr isn't a terminal or the output is logged
+    \\  --summary                      Write the selected projects, the gradle runs and their results into the given json file
+    \\  --junit                        Write a junit xml report into the given file, one test case per project
+    \\  --continue                     Keep running the other batches after one fails, and report all failures at the end
+    \\  --skip-dir                     Don't scan directories with the given name, besides build and node_modules
+    \\  --name-rule                    Rewrite the end of project names like :android=-android, replacing the default :android and :domain rules
+    \\  --max-depth                    Descend at most n directory levels, 3 by default
     \\  -d, --with-dependency-projects Include local projects in the dependencies too
+    \\  --only-changed                 Only select the changed projects, even -d or with_dependency_projects is given
+    \\  --fail-on-empty                Fail if no project is selected, instead of doing nothing
+    \\  -n, --dry-run                  Generate the settings file and print the gradle command instead of running it
+    \\  -l, --list                     Print the selected projects instead of generating the settings file
+    \\  --status                       Print the branch, the compare commit, the changed files and the selected projects, then stop
+    \\  --explain                      Print why each selected project is picked into stderr
+    \\  --format                       The format of --list: json, names or paths, json by default
+    \\  -g, --graph                    Write the dependency graph of all scanned projects into the given file
+    \\  --graph-format                 The format of --graph: dot or json, dot by default
+    \\  --reuse-selection              Pick the projects of the last run again while HEAD, the selecting options
+    \\                                 and the uncommitted files (unless --diff-target head) are the same
+    \\  --no-cache                     Parse all build files again instead of reusing the dependencies cached by earlier runs
+    \\  --timings                      Print how long scanning, selecting, reading dependencies, writing settings and gradle take
+    \\  -q, --quiet                    Only log errors
+    \\  --no-color                     Don't color the logs even stderr is a terminal
     \\  -h, --help                     Print command-specific usage
     \\  -V, --version                  Print version
     \\
     \\Environments:
     \\
-    \\ GRADLE_CMD                      The gradel command to run for building, you can give args here too
+    \\ GRADLE_CMD                      The gradel command to run for building, you can give args and $VARS here too,
+    \\                                 ./gradlew by default, or gradle in PATH if there is no gradlew
+    \\ ABT_MAX_DEPTH                   The default value of --max-depth
+    \\ GITHUB_ACTIONS                  Group the gradle output and annotate the failures if it is true
+    \\ NO_COLOR                        Don't color the logs if it is not empty
+    \\
+    \\Config file:
+    \\
+    \\ .abt.toml in the git root gives defaults by `key = value` lines, options and environments override them.
+    \\ Keys are base_branch, regexp, invert_match, exclude, filter, include, gradle_cmd, threshold, jobs, max_depth, skip_dirs, name_rules, per_gradle_root and with_dependency_projects.
+    \\
+    \\Exit status:
+    \\
+    \\ 0                               Success
+    \\ 1                               Other failures
+    \\ 2                               Invalid options or config file
+    \\ 3                               Gradle failed or timed out
+    \\ 4                               Git failed
+    \\ 5                               No project is selected with --fail-on-empty
     \\
 ;
 
 fn nextOrFatal(it: *std.process.ArgIterator, cur: []const u8) [:0]const u8 {
-    return it.next() orelse fatal("expected parameter after {s}", .{cur});
+    return it.next() orelse fatalWith(.usage, "expected parameter after {s}", .{cur});
+}
+/// The options followed by a value, which is skipped when looking for `-q` before the options are parsed.
+const value_options = [_][]const u8{
+    "-C",              "--root",      "-s",              "--since-commit", "--diff-target", "--base-branch",
+    "-i",              "--include",   "-e",              "--regexp",       "-v",            "--invert-match",
+    "-x",              "--exclude",   "--projects-file", "-f",             "--filter",      "-c",
+    "--settings-file", "--threshold", "-j",              "--jobs",         "-t",            "--task",
+    "--gradle-arg",    "--timeout",   "--log-file",      "--summary",      "--junit",       "--skip-dir",
+    "--name-rule",     "--max-depth", "--format",        "-g",             "--graph",       "--graph-format",
+};
+
+/// Whether `-q` is among the options, the args after the gradle command are for gradle.
+fn quietGiven(args: anytype) bool {
+    next: while (args.next()) |arg| {
+        if (mem.eql(u8, arg, "-q") or mem.eql(u8, arg, "--quiet")) return true;
+        if (mem.eql(u8, arg, "--") or !mem.startsWith(u8, arg, "-")) return false;
+        for (value_options) |option| {
+            if (mem.eql(u8, arg, option)) {
+                _ = args.next();
+                continue :next;
+            }
+        }
+    }
+    return false;
+}
+/// The first given rule drops the default ones.
+fn appendNameRule(allocator: Allocator, options: *Options, rule: []const u8) !void {
+    if (options.name_rules == null) {
+        options.name_rules = .{};
+    }
+    try options.name_rules.?.append(allocator, NameRule.parse(rule));
+}
+fn parseMaxDepth(value: []const u8) usize {
+    const max_depth = std.fmt.parseInt(usize, value, 10) catch fatalWith(.usage, "Invalid max depth '{s}'", .{value});
+    if (max_depth < 1 or max_depth > max_depth_allowed) {
+        fatalWith(.usage, "Max depth should be between 1 and {}, got {}", .{ max_depth_allowed, max_depth });
+    }
+    return max_depth;
 }
 pub fn main() !void {
     var arena = std.heap.ArenaAllocator.init(std.heap.page_allocator);
     defer arena.deinit();
     const allocator = arena.allocator();
 
+    const no_color = if (std.posix.getenv("NO_COLOR")) |value| value.len > 0 else false;
+    log_color = !no_color and io.getStdErr().isTty();
+
+    // finding the git root and loading the config file log already
+    var quiet_args = try process.argsWithAllocator(allocator);
+    _ = quiet_args.skip();
+    if (quietGiven(&quiet_args)) {
+        log_level = .err;
+    }
+
+    // the root decides which config file is read, so like `git -C` it has to lead the other options
+    var skipped = @as(usize, 1); // program path
+    var root_args = try process.argsWithAllocator(allocator);
+    _ = root_args.skip();
+    while (root_args.next()) |arg| {
+        if (!mem.eql(u8, arg, "-C") and !mem.eql(u8, arg, "--root")) break;
+        const dir = nextOrFatal(&root_args, arg);
+        std.posix.chdir(dir) catch |e| fatalWith(.usage, "Can't change directory to {s}: {}", .{ dir, e });
+        debug("Use {s} as the root", .{dir});
+        skipped += 2;
+    }
+
     var args = try process.argsWithAllocator(allocator);
 
     var options = Options{
         .includes = StringHashMap(void).init(allocator),
         .commands = std.ArrayList([]const u8).init(allocator),
     };
+    const vc_root = gitRoot(allocator);
+    if (vc_root) |root| {
+        try loadConfigFile(allocator, root, &options);
+    }
+    if (std.posix.getenv("ABT_MAX_DEPTH")) |max_depth| {
+        options.max_depth = parseMaxDepth(max_depth);
+    }
     const cwd = try std.fs.cwd().realpathAlloc(allocator, ".");
-    _ = args.skip(); // skip program path
+    for (0..skipped) |_| {
+        _ = args.skip(); // skip program path and roots
+    }
+    var name_rule_given = false;
     while (args.next()) |arg| {
         if (mem.eql(u8, arg, "-V") or mem.eql(u8, arg, "--version")) {
             return io.getStdOut().writer().print("version: {s}\n", .{config.version});
@@ -65,26 +225,97 @@ pub fn main() !void {
             break;
         }
 
-        if (mem.eql(u8, arg, "-s") or mem.eql(u8, arg, "--since-commit")) {
+        if (mem.eql(u8, arg, "-C") or mem.eql(u8, arg, "--root")) {
+            fatalWith(.usage, "{s} should be given before other options", .{arg});
+        } else if (mem.eql(u8, arg, "-s") or mem.eql(u8, arg, "--since-commit")) {
             options.since_commit = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--diff-target")) {
+            const target = nextOrFatal(&args, arg);
+            options.diff_target = std.meta.stringToEnum(DiffTarget, target) orelse fatalWith(.usage, "Unknown diff target '{s}'", .{target});
+        } else if (mem.eql(u8, arg, "--base-branch")) {
+            options.base_branch = nextOrFatal(&args, arg);
         } else if (mem.eql(u8, arg, "-i") or mem.eql(u8, arg, "--include")) {
             try options.includes.put(try std.fs.path.resolve(allocator, &[_][]const u8{ cwd, nextOrFatal(&args, arg) }), {});
         } else if (mem.eql(u8, arg, "-e") or mem.eql(u8, arg, "--regexp")) {
             options.regexp = nextOrFatal(&args, arg);
         } else if (mem.eql(u8, arg, "-v") or mem.eql(u8, arg, "--invert-match")) {
             options.invert_match = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "-x") or mem.eql(u8, arg, "--exclude")) {
+            try options.excludes.append(allocator, nextOrFatal(&args, arg));
+        } else if (mem.eql(u8, arg, "--projects-file")) {
+            const path = nextOrFatal(&args, arg);
+            const content = std.fs.cwd().readFileAlloc(allocator, path, 100_000_000) catch |e| fatalWith(.usage, "Can't read projects file {s}: {}", .{ path, e });
+            try options.forced_projects.appendSlice(allocator, try parseProjectsFile(allocator, content));
         } else if (mem.eql(u8, arg, "-f") or mem.eql(u8, arg, "--filter")) {
             options.filter = nextOrFatal(&args, arg);
         } else if (mem.eql(u8, arg, "-c") or mem.eql(u8, arg, "--settings-file")) {
             options.settings_file = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--per-gradle-root")) {
+            options.per_gradle_root = true;
         } else if (mem.eql(u8, arg, "--threshold")) {
-            options.threshold = try std.fmt.parseInt(usize, nextOrFatal(&args, arg), 10);
+            const threshold = nextOrFatal(&args, arg);
+            options.threshold = std.fmt.parseInt(usize, threshold, 10) catch fatalWith(.usage, "Invalid threshold '{s}'", .{threshold});
+        } else if (mem.eql(u8, arg, "-j") or mem.eql(u8, arg, "--jobs")) {
+            const jobs = nextOrFatal(&args, arg);
+            options.jobs = @max(1, std.fmt.parseInt(usize, jobs, 10) catch fatalWith(.usage, "Invalid jobs '{s}'", .{jobs}));
+        } else if (mem.eql(u8, arg, "--fail-on-empty")) {
+            options.fail_on_empty = true;
+        } else if (mem.eql(u8, arg, "-n") or mem.eql(u8, arg, "--dry-run")) {
+            options.dry_run = true;
+        } else if (mem.eql(u8, arg, "-l") or mem.eql(u8, arg, "--list")) {
+            options.list = true;
+        } else if (mem.eql(u8, arg, "--status")) {
+            options.status = true;
+        } else if (mem.eql(u8, arg, "--explain")) {
+            options.explain = true;
+        } else if (mem.eql(u8, arg, "--format")) {
+            const format = nextOrFatal(&args, arg);
+            options.format = std.meta.stringToEnum(ListFormat, format) orelse fatalWith(.usage, "Unknown format '{s}'", .{format});
+        } else if (mem.eql(u8, arg, "-g") or mem.eql(u8, arg, "--graph")) {
+            options.graph = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--graph-format")) {
+            const format = nextOrFatal(&args, arg);
+            options.graph_format = std.meta.stringToEnum(GraphFormat, format) orelse fatalWith(.usage, "Unknown graph format '{s}'", .{format});
+        } else if (mem.eql(u8, arg, "--reuse-selection")) {
+            options.reuse_selection = true;
+        } else if (mem.eql(u8, arg, "--no-cache")) {
+            options.cache = false;
+        } else if (mem.eql(u8, arg, "-t") or mem.eql(u8, arg, "--task")) {
+            options.task = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--gradle-arg")) {
+            try options.gradle_args.append(allocator, nextOrFatal(&args, arg));
+        } else if (mem.eql(u8, arg, "--timeout")) {
+            const timeout = nextOrFatal(&args, arg);
+            options.timeout = std.fmt.parseInt(u64, timeout, 10) catch fatalWith(.usage, "Invalid timeout '{s}'", .{timeout});
+        } else if (mem.eql(u8, arg, "--log-file")) {
+            options.log_file = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--timings")) {
+            options.timings = true;
+        } else if (mem.eql(u8, arg, "-q") or mem.eql(u8, arg, "--quiet")) {
+            log_level = .err;
+        } else if (mem.eql(u8, arg, "--no-color")) {
+            log_color = false;
+        } else if (mem.eql(u8, arg, "--summary")) {
+            options.summary = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--junit")) {
+            options.junit = nextOrFatal(&args, arg);
+        } else if (mem.eql(u8, arg, "--continue")) {
+            options.keep_going = true;
+        } else if (mem.eql(u8, arg, "--skip-dir")) {
+            try options.skip_dirs.append(allocator, nextOrFatal(&args, arg));
+        } else if (mem.eql(u8, arg, "--name-rule")) {
+            // the rules of the config file are replaced like the default ones
+            if (!name_rule_given) {
+                options.name_rules = null;
+                name_rule_given = true;
+            }
+            try appendNameRule(allocator, &options, nextOrFatal(&args, arg));
         } else if (mem.eql(u8, arg, "--max-depth")) {
-            const max_depth = try std.fmt.parseInt(usize, nextOrFatal(&args, arg), 10);
-            std.debug.assert(max_depth > 1 and max_depth <= max_depth_allowed);
-            options.max_depth = max_depth;
+            options.max_depth = parseMaxDepth(nextOrFatal(&args, arg));
         } else if (mem.eql(u8, arg, "-d") or mem.eql(u8, arg, "--with-dependency-projects")) {
             options.include_local_dependencies = true;
+        } else if (mem.eql(u8, arg, "--only-changed")) {
+            options.only_changed = true;
         } else {
             try options.commands.append(arg);
             break;
@@ -97,33 +328,283 @@ pub fn main() !void {
     }
     debug("parse options: {}", .{options});
 
-    return build(allocator, &options);
+    return build(allocator, &options, vc_root);
 }
 
-fn build(allocator: Allocator, options: *Options) !void {
+fn gitHead(allocator: Allocator, vc_root: []const u8) ?[]const u8 {
+    const output = exec(allocator, &[_][]const u8{
+        "git",
+        "rev-parse",
+        "HEAD",
+    }, vc_root) catch |e| {
+        warn("Find HEAD commit fail: {}", .{e});
+        return null;
+    };
+    return mem.trimRight(u8, output, "\n");
+}
+
+/// The path of a file in the git dir, which isn't `.git` of the root in worktrees and submodules.
+fn gitPath(allocator: Allocator, vc_root: []const u8, name: []const u8) ![]const u8 {
+    const output = try exec(allocator, &[_][]const u8{
+        "git",
+        "rev-parse",
+        "--git-path",
+        name,
+    }, vc_root);
+    return std.fs.path.resolve(allocator, &[_][]const u8{ vc_root, mem.trimRight(u8, output, "\n") });
+}
+
+fn gitRoot(allocator: Allocator) ?[]const u8 {
     const output = exec(allocator, &[_][]const u8{
         "git",
         "rev-parse",
         "--show-toplevel",
-    }, null) catch |e| blk: {
+    }, null) catch |e| {
         warn("Find git root fail: {}", .{e});
-        break :blk null;
+        return null;
     };
-    const vc_root = if (output) |root| blk: {
-        const dir = mem.trimRight(u8, root, "\n");
+    return mem.trimRight(u8, output, "\n");
+}
+
+fn build(allocator: Allocator, options: *Options, vc_root: ?[]const u8) !void {
+    if (vc_root) |dir| {
         debug("Add git root {s} as one root", .{dir});
         try options.includes.put(dir, {});
-        break :blk dir;
-    } else blk: {
+    } else {
         debug("Not in a git dir", .{});
-        break :blk null;
-    };
+    }
 
     var projects = Projects.init(allocator);
+    if (options.name_rules) |rules| {
+        projects.name_rules = rules.items;
+    }
+    projects.skip_dirs = try mem.concat(allocator, []const u8, &[_][]const []const u8{ &default_skip_dirs, options.skip_dirs.items });
+    var cache: ?DependencyCache = null;
+    if (options.cache) {
+        if (vc_root) |root| {
+            if (gitPath(allocator, root, DependencyCache.file_name)) |path| {
+                cache = try DependencyCache.load(allocator, path);
+            } else |e| {
+                warn("Can't find the dependency cache of {s}: {}", .{ root, e });
+            }
+        }
+    }
+    if (cache) |*c| {
+        projects.cache = c;
+    }
+    defer if (projects.cache) |c| c.save();
+    var timings = Timings{};
+    defer if (options.timings) timings.print();
+    // gradle draws its own progress, so it ends before running gradle
+    const progress = if (log_level != .err and io.getStdErr().isTty()) std.Progress.start(.{ .root_name = "Select projects" }) else std.Progress.Node.none;
+    projects.progress = progress;
+    var compare_commit: ?[]const u8 = null;
+    var head: ?[]const u8 = null;
+    var selection_path: ?[]const u8 = null;
+    if (options.reuse_selection) {
+        if (vc_root) |root| {
+            head = gitHead(allocator, root);
+            selection_path = gitPath(allocator, root, LastSelection.file_name) catch |e| blk: {
+                warn("Can't find the last selection of {s}: {}", .{ root, e });
+                break :blk null;
+            };
+        }
+    }
+    var key = selectionKey(options);
+    if (head != null and options.diff_target != .head) {
+        // the uncommitted files are compared too, any change of them may select other projects
+        if (exec(allocator, &[_][]const u8{ "git", "status", "--porcelain", "--untracked-files=all" }, vc_root.?)) |changes| {
+            key = std.hash.Wyhash.hash(key, changes);
+        } else |e| {
+            warn("Can't list the uncommitted changes, select again: {}", .{e});
+            head = null;
+        }
+    }
+    const reused = if (head != null and selection_path != null) try LastSelection.load(allocator, selection_path.?, head.?, key) else null;
+    if (reused) |selection| {
+        info("Reuse the {} projects selected by the last run", .{selection.entries.len});
+        try projects.entries[@intFromEnum(Projects.State.Picked)].appendSlice(selection.entries);
+        compare_commit = selection.compare_commit;
+        projects.changed_files = selection.changed_files;
+    } else {
+        compare_commit = try select(allocator, options, vc_root, &projects, &timings);
+        if (head != null and selection_path != null) {
+            const selection = LastSelection.Selection{
+                .compare_commit = compare_commit,
+                .changed_files = projects.changed_files,
+                .entries = projects.entries[@intFromEnum(Projects.State.Picked)].items,
+            };
+            LastSelection.save(allocator, selection_path.?, head.?, key, selection) catch |e| warn("Can't save the selection: {}", .{e});
+        }
+    }
+    projects.sortPicked();
+    progress.end();
+    if (options.explain) {
+        try explain(io.getStdErr().writer(), projects.entries[@intFromEnum(Projects.State.Picked)].items);
+    }
+    if (options.graph) |output| {
+        return graph(allocator, &projects, output, options.graph_format);
+    }
+    if (projects.entries[@intFromEnum(Projects.State.Picked)].items.len == 0) {
+        if (options.fail_on_empty) {
+            // exiting skips the deferred save
+            if (projects.cache) |c| c.save();
+            fatalWith(.empty, "No project is selected", .{});
+        }
+        info("No project is selected", .{});
+    }
+    if (options.status) {
+        return printStatus(allocator, vc_root, compare_commit, projects.changed_files, projects.entries[@intFromEnum(Projects.State.Picked)].items);
+    }
+    if (options.list) {
+        return list(allocator, projects.entries[@intFromEnum(Projects.State.Picked)].items, options.format);
+    }
+
+    const partitions = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+    const current_root = [_]GradleRoot{.{ .first = 0, .projects = partitions }};
+    const roots = if (options.per_gradle_root) try groupByGradleRoot(allocator, partitions) else &current_root;
+    if (partitions.len > 0 and options.runsGradle()) {
+        var gradle_cmd = std.ArrayList([]const u8).init(allocator);
+        const env_cmd: ?[]const u8 = std.posix.getenv("GRADLE_CMD");
+        if (env_cmd orelse options.gradle_cmd) |cmd| {
+            var env = try std.process.getEnvMap(allocator);
+            var words = mem.tokenize(u8, try expandEnv(allocator, cmd, &env), " ");
+            while (words.next()) |arg| {
+                try gradle_cmd.append(arg);
+            }
+            debug("Gradle command is : {s}", .{gradle_cmd.items});
+        }
+
+        var batches = ArrayList(Batch).init(allocator);
+        for (roots) |root| {
+            const settings_file = try root.settingsFile(allocator, options);
+            const root_cmd = if (gradle_cmd.items.len > 0) gradle_cmd.items else try defaultGradleCommand(root.dir);
+            const size = options.threshold orelse if (options.jobs > 1) (root.projects.len + options.jobs - 1) / options.jobs else default_threshold;
+            var i = @as(usize, 0);
+            for (try splitBatches(allocator, root.projects, size, projects.cache)) |batch_projects| {
+                const file = if (options.jobs > 1) try batchSettingsFile(allocator, settings_file, batches.items.len + 1) else settings_file;
+                try batches.append(.{
+                    .first = root.first + i,
+                    .projects = batch_projects,
+                    .settings_file = file,
+                    .cwd = root.dir,
+                    .command = try gradleCommand(allocator, root_cmd, file, options.gradle_args.items, if (options.task) |task| try projectTasks(allocator, batch_projects, task, options.commands.items) else options.commands.items),
+                });
+                i += batch_projects.len;
+            }
+        }
+        // gradle failing exits without the deferred save, all the build files are read by now
+        if (projects.cache) |c| c.save();
+        if (options.dry_run) {
+            const stdout = io.getStdOut().writer();
+            for (batches.items) |batch| {
+                const writing = Timings.start();
+                try write(allocator, batch.projects, batch.settings_file);
+                timings.record(.settings, writing);
+                if (batch.cwd) |dir| {
+                    try stdout.print("cd {s} && ", .{dir});
+                }
+                for (batch.command, 0..) |word, n| {
+                    try stdout.print("{s}{s}", .{ if (n == 0) "" else " ", word });
+                }
+                try stdout.writeByte('\n');
+            }
+            return;
+        }
+        for (roots) |root| {
+            const program = if (gradle_cmd.items.len > 0) gradle_cmd.items[0] else (try defaultGradleCommand(root.dir))[0];
+            try checkCommand(allocator, root.dir, program);
+        }
+        var log: ?Log = null;
+        if (options.log_file) |path| {
+            log = .{ .file = std.fs.cwd().createFile(path, .{ .truncate = true }) catch |e| fatal("Can't create log file {s} {}", .{ path, e }) };
+        }
+        defer if (log) |l| l.file.close();
+        const github_actions = if (std.posix.getenv("GITHUB_ACTIONS")) |value| mem.eql(u8, value, "true") else false;
+        var runner = Runner{
+            .timeout = options.timeout,
+            .log = if (log) |*l| l else null,
+            .github_actions = github_actions,
+            // groups of concurrent batches would interleave
+            .group_output = github_actions and options.jobs == 1,
+            .keep_going = options.keep_going,
+        };
+        if (options.timeout != null and builtin.os.tag != .windows) {
+            passInterrupts() catch |e| warn("Can't pass Ctrl-C on to gradle: {}", .{e});
+        }
+        const started = std.time.milliTimestamp();
+        if (options.jobs > 1) {
+            const writing = Timings.start();
+            for (batches.items) |batch| {
+                try write(allocator, batch.projects, batch.settings_file);
+            }
+            timings.record(.settings, writing);
+            const running = Timings.start();
+            try runConcurrently(batches.items, partitions.len, options.jobs, &runner);
+            timings.record(.gradle, running);
+        } else {
+            for (batches.items) |*batch| {
+                info("Execute {}:{}/{} {s}", .{ batch.first + 1, batch.first + batch.projects.len, partitions.len, batch.command });
+                const writing = Timings.start();
+                try write(allocator, batch.projects, batch.settings_file);
+                timings.record(.settings, writing);
+                const running = Timings.start();
+                batch.run(allocator, &runner);
+                timings.record(.gradle, running);
+                if (!batch.succeeded() and !options.keep_going) break;
+            }
+        }
+        if (options.junit) |path| {
+            writeJunit(path, batches.items) catch |e| warn("Can't write junit report {s}: {}", .{ path, e });
+        }
+        if (options.summary) |path| {
+            writeSummary(allocator, path, compare_commit, batches.items, elapsedMillis(started)) catch |e| warn("Can't write summary {s}: {}", .{ path, e });
+        }
+        var failures = @as(usize, 0);
+        for (batches.items) |batch| {
+            if (!batch.ran() or batch.succeeded()) continue;
+            failures += 1;
+            if (batch.err) |e| {
+                std.log.err("Execute command failed: {s} {}", .{ batch.command, e });
+            } else {
+                std.log.err("Execute command failed: {s} {}", .{ batch.command, batch.term.? });
+            }
+            for (batch.projects) |p| {
+                std.log.err("Failed project: {s}", .{p.name});
+            }
+            const tail = batch.stderr_tail.lines();
+            if (tail.len > 0) {
+                std.log.err("The end of the gradle stderr:\n{s}", .{tail});
+            }
+        }
+        if (failures > 0) {
+            // exiting skips the deferred print
+            if (options.timings) timings.print();
+            fatalWith(.build, "{} of {} gradle runs failed", .{ failures, batches.items.len });
+        }
+    } else {
+        const writing = Timings.start();
+        for (roots) |root| {
+            try write(allocator, root.projects, try root.settingsFile(allocator, options));
+        }
+        timings.record(.settings, writing);
+    }
+}
+
+/// Scans the roots and picks the projects by the options, returns the commit the changes are compared with.
+fn select(allocator: Allocator, options: *Options, vc_root: ?[]const u8, projects: *Projects, timings: *Timings) !?[]const u8 {
+    const scanning = Timings.start();
+    var roots = ArrayList([]const u8).init(allocator);
     var iter = options.includes.keyIterator();
     while (iter.next()) |root| {
-        try projects.scan(root.*, options.max_depth);
+        try roots.append(root.*);
     }
+    try projects.scanAll(roots.items, options.max_depth);
+    if (options.per_gradle_root) {
+        try projects.rebaseOnGradleRoots();
+    }
+    timings.record(.scan, scanning);
+    const selecting = Timings.start();
     if (options.regexp) |pattern| {
         try projects.pick(pattern);
     } else {
@@ -132,7 +613,14 @@ fn build(allocator: Allocator, options: *Options) !void {
     if (options.invert_match) |pattern| {
         try projects.deny(pattern);
     }
-    if (options.since_commit) |commit| {
+    if (options.excludes.items.len > 0) {
+        try projects.denyNames(options.excludes.items);
+    }
+    if (options.only_changed and options.since_commit == null and options.base_branch == null) {
+        fatalWith(.usage, "--only-changed needs --since-commit or --base-branch", .{});
+    }
+    var compare_commit: ?[]const u8 = null;
+    if (options.since_commit orelse options.base_branch) |commit| {
         if (vc_root) |root| {
             const base = if (spawn(allocator, &[_][]const u8{
                 "git", "merge-base", "--is-ancestor", commit, "HEAD",
@@ -153,75 +641,453 @@ fn build(allocator: Allocator, options: *Options) !void {
                 warn("Call git merge-base failed {}, use the commit {s} directly", .{ e, commit });
                 break :brk commit;
             };
-            try projects.denyUnchanged(root, base, max_depth_allowed);
+            compare_commit = base;
+            try projects.denyUnchanged(root, base, max_depth_allowed, options.diff_target);
         }
     }
     if (options.filter) |pattern| {
         try projects.filter(pattern);
     }
-    if (options.include_local_dependencies) {
+    if (options.forced_projects.items.len > 0) {
+        try projects.force(options.forced_projects.items);
+    }
+    timings.record(.select, selecting);
+    if (options.include_local_dependencies and !options.only_changed) {
+        const reading = Timings.start();
         try projects.add_local_dependencies();
+        timings.record(.dependencies, reading);
     }
+    return compare_commit;
+}
 
-    const settings_file = options.settings_file orelse if (options.commands.items.len > 0) "build.settings.gradle.kts" else "settings.gradle.kts";
-    var partitions = projects.entries[@intFromEnum(Projects.State.Picked)].items;
-    if (partitions.len > 0 and options.commands.items.len > 0) {
-        var gradle_cmd = try std.ArrayList([]const u8).initCapacity(allocator, options.commands.items.len + 3);
-        if (std.posix.getenvZ("GRADLE_CMD")) |cmd| {
-            var words = mem.tokenize(u8, cmd, " ");
-            while (words.next()) |arg| {
-                try gradle_cmd.append(arg);
-            }
-        } else {
-            try gradle_cmd.append("./gradlew");
-        }
-        try gradle_cmd.appendSlice(options.commands.items);
-        try gradle_cmd.append("-c");
-        try gradle_cmd.append(settings_file);
-        const command = gradle_cmd.items;
-        debug("Gradle command is : {s}", .{command});
+const DiffTarget = enum(u8) { workdir, index, head };
 
-        var i = @as(usize, 0);
-        while (i < partitions.len) {
-            const end = @min(partitions.len, i + options.threshold);
-            info("Execute {}:{}/{} {s}", .{ i + 1, end, partitions.len, command });
-            try write(allocator, partitions[i..end], settings_file);
-            i = end;
-            if (spawn(allocator, command, null)) |term| {
-                if (term.Exited != 0) {
-                    fatal("Execute command failed: {s} {}", .{ command, term.Exited });
-                }
-            } else |e| {
-                fatal("Execute command failed: {s} {}", .{ command, e });
-            }
+const max_depth_allowed = 5;
+/// Rewrites the end of project names, so `feature:foo:android` is named `feature:foo-android`.
+const NameRule = struct {
+    from: []const u8,
+    to: []const u8,
+
+    fn parse(rule: []const u8) NameRule {
+        const eq = mem.indexOfScalar(u8, rule, '=') orelse fatalWith(.usage, "Name rule should be like from=to, got {s}", .{rule});
+        if (eq == 0) {
+            fatalWith(.usage, "Name rule should be like from=to, got {s}", .{rule});
         }
-    } else {
-        try write(allocator, partitions, settings_file);
+        return .{ .from = rule[0..eq], .to = rule[eq + 1 ..] };
     }
-}
-
-const max_depth_allowed = 5;
+};
+const default_name_rules = [_]NameRule{
+    .{ .from = ":android", .to = "-android" },
+    .{ .from = ":domain", .to = "-domain" },
+};
+/// Build outputs and vendored copies may have stray build files, they are never scanned.
+const default_skip_dirs = [_][]const u8{ "build", "node_modules" };
+const max_jobs = 64;
+const default_threshold = 1000;
 const Options = struct {
     since_commit: ?[]const u8 = null,
+    /// Used as the since commit when it isn't given.
+    base_branch: ?[]const u8 = null,
+    diff_target: DiffTarget = .workdir,
     includes: StringHashMap(void),
     regexp: ?[:0]const u8 = null,
     invert_match: ?[:0]const u8 = null,
+    excludes: std.ArrayListUnmanaged([]const u8) = .{},
+    /// Selected even they are unchanged or denied.
+    forced_projects: std.ArrayListUnmanaged([:0]const u8) = .{},
     filter: ?[:0]const u8 = null,
     settings_file: ?[]const u8 = null,
-    threshold: usize = 1000,
+    per_gradle_root: bool = false,
+    /// `default_threshold`, or the projects split evenly among the jobs if null.
+    threshold: ?usize = null,
     max_depth: usize = 3,
     include_local_dependencies: bool = false,
+    only_changed: bool = false,
+    skip_dirs: std.ArrayListUnmanaged([]const u8) = .{},
+    name_rules: ?std.ArrayListUnmanaged(NameRule) = null,
+    jobs: usize = 1,
+    gradle_cmd: ?[]const u8 = null,
+    gradle_args: std.ArrayListUnmanaged([]const u8) = .{},
+    task: ?[]const u8 = null,
+    cache: bool = true,
+    reuse_selection: bool = false,
+    timeout: ?u64 = null,
+    log_file: ?[]const u8 = null,
+    junit: ?[]const u8 = null,
+    summary: ?[]const u8 = null,
+    timings: bool = false,
+    keep_going: bool = false,
+    fail_on_empty: bool = false,
+    dry_run: bool = false,
+    list: bool = false,
+    explain: bool = false,
+    status: bool = false,
+    format: ListFormat = .json,
+    graph: ?[]const u8 = null,
+    graph_format: GraphFormat = .dot,
     commands: std.ArrayList([]const u8),
+
+    fn runsGradle(self: *const Options) bool {
+        return self.commands.items.len > 0 or self.task != null;
+    }
+};
+
+/// One gradle run over a slice of the picked projects.
+const Batch = struct {
+    first: usize,
+    projects: []Projects.Entry,
+    settings_file: []const u8,
+    /// The dir gradle runs in, the current dir if null.
+    cwd: ?[]const u8 = null,
+    command: []const []const u8,
+    term: ?std.process.Child.Term = null,
+    err: ?anyerror = null,
+    duration_ms: u64 = 0,
+    stderr_tail: Tail = .{},
+
+    fn run(self: *Batch, allocator: Allocator, runner: *Runner) void {
+        if (runner.group_output) {
+            const stdout = io.getStdOut().writer();
+            if (self.projects.len == 1) {
+                stdout.print("::group::Build {s}\n", .{self.projects[0].name}) catch {};
+            } else {
+                stdout.print("::group::Build {s} and {} more projects\n", .{ self.projects[0].name, self.projects.len - 1 }) catch {};
+            }
+        }
+        const started = std.time.milliTimestamp();
+        if (runner.run(allocator, self.command, self.cwd, &self.stderr_tail)) |term| {
+            self.term = term;
+        } else |e| {
+            self.err = e;
+        }
+        self.duration_ms = elapsedMillis(started);
+        if (runner.group_output) {
+            io.getStdOut().writeAll("::endgroup::\n") catch {};
+        }
+        if (runner.github_actions and !self.succeeded()) {
+            self.annotateFailure(allocator) catch |e| warn("Can't annotate the failure: {}", .{e});
+        }
+    }
+
+    /// Prints a GitHub Actions error annotation naming the projects of the batch.
+    fn annotateFailure(self: Batch, allocator: Allocator) !void {
+        var line = ArrayList(u8).init(allocator);
+        defer line.deinit();
+        const writer = line.writer();
+        try writer.writeAll("::error title=abt::Build of ");
+        for (self.projects, 0..) |p, i| {
+            if (i > 0) try writer.writeAll(", ");
+            try writer.writeAll(p.name);
+        }
+        try writer.writeAll(" failed, ");
+        if (self.err) |e| {
+            try writer.writeAll(@errorName(e));
+        } else {
+            try writeTerm(writer, self.term.?);
+        }
+        try writer.writeByte('\n');
+        try io.getStdOut().writeAll(line.items);
+    }
+
+    fn ran(self: Batch) bool {
+        return self.term != null or self.err != null;
+    }
+
+    fn succeeded(self: Batch) bool {
+        const term = self.term orelse return false;
+        return switch (term) {
+            .Exited => |code| code == 0,
+            else => false,
+        };
+    }
+};
+
+/// The projects gradle builds together, from the wrapper in `dir`.
+const GradleRoot = struct {
+    /// The current dir if null.
+    dir: ?[]const u8 = null,
+    /// The index of the first project in all picked ones.
+    first: usize,
+    projects: []Projects.Entry,
+
+    fn settingsFile(self: GradleRoot, allocator: Allocator, options: *const Options) ![]const u8 {
+        const path = self.dir orelse return defaultSettingsFile(options, std.fs.cwd());
+        var dir = try std.fs.openDirAbsolute(path, .{});
+        defer dir.close();
+        return std.fs.path.join(allocator, &[_][]const u8{ path, defaultSettingsFile(options, dir) });
+    }
 };
+
+fn defaultSettingsFile(options: *const Options, dir: fs.Dir) []const u8 {
+    return options.settings_file orelse switch (SettingsDsl.detect(dir)) {
+        .Kotlin => if (options.runsGradle()) "build.settings.gradle.kts" else "settings.gradle.kts",
+        .Groovy => if (options.runsGradle()) "build.settings.gradle" else "settings.gradle",
+    };
+}
+
+/// Reorders the projects so the ones of a gradle root are next to each other, roots keep the order
+/// they are first seen, projects without a gradle wrapper above them go with the current dir.
+fn groupByGradleRoot(allocator: Allocator, projects: []Projects.Entry) ![]GradleRoot {
+    const dirs = try allocator.alloc(?[]const u8, projects.len);
+    for (projects, dirs) |p, *dir| {
+        dir.* = try gradleRoot(allocator, p);
+    }
+    const unordered = try allocator.dupe(Projects.Entry, projects);
+    var roots = ArrayList(GradleRoot).init(allocator);
+    var next = @as(usize, 0);
+    next_root: for (dirs, 0..) |dir, i| {
+        for (roots.items) |root| {
+            if (sameDir(root.dir, dir)) continue :next_root;
+        }
+        const first = next;
+        for (dirs[i..], unordered[i..]) |d, p| {
+            if (sameDir(d, dir)) {
+                projects[next] = p;
+                next += 1;
+            }
+        }
+        debug("Gradle root {?s} has {} projects", .{ dir, next - first });
+        try roots.append(.{ .dir = dir, .first = first, .projects = projects[first..next] });
+    }
+    return roots.toOwnedSlice();
+}
+
+/// The nearest dir of the project, itself included, having a gradle wrapper.
+fn gradleRoot(allocator: Allocator, project: Projects.Entry) !?[]const u8 {
+    var dir: []const u8 = try std.fs.path.join(allocator, &[_][]const u8{ project.root, project.path });
+    while (true) {
+        const wrapper = try std.fs.path.join(allocator, &[_][]const u8{ dir, "gradlew" });
+        if (std.fs.accessAbsolute(wrapper, .{})) |_| {
+            return dir;
+        } else |_| {}
+        dir = std.fs.path.dirname(dir) orelse return null;
+    }
+}
+
+fn sameDir(a: ?[]const u8, b: ?[]const u8) bool {
+    if (a == null or b == null) return a == null and b == null;
+    return mem.eql(u8, a.?, b.?);
+}
+
+/// The settings file goes before the gradle args and the tasks, so `-c` is never taken as an arg of theirs.
+fn gradleCommand(allocator: Allocator, gradle_cmd: []const []const u8, settings_file: []const u8, gradle_args: []const []const u8, tasks: []const []const u8) ![]const []const u8 {
+    return mem.concat(allocator, []const u8, &[_][]const []const u8{ gradle_cmd, &[_][]const u8{ "-c", settings_file }, gradle_args, tasks });
+}
+
+const wrapper_command = [_][]const u8{"./gradlew"};
+const system_command = [_][]const u8{"gradle"};
+
+/// The wrapper of the gradle root, or gradle in PATH if the root has no wrapper, running a missing
+/// wrapper would fail with a cryptic error.
+fn defaultGradleCommand(dir_path: ?[]const u8) ![]const []const u8 {
+    var dir = if (dir_path) |path| try std.fs.openDirAbsolute(path, .{}) else std.fs.cwd();
+    defer if (dir_path != null) dir.close();
+    if (dir.access("gradlew", .{})) |_| {
+        return &wrapper_command;
+    } else |_| {}
+    if (inPath("gradle")) {
+        info("No gradlew in {s}, use gradle in PATH", .{dir_path orelse "."});
+        return &system_command;
+    }
+    fatalWith(.usage, "Neither gradlew in {s} nor gradle in PATH is found, run abt in the gradle root or set GRADLE_CMD", .{dir_path orelse "."});
+}
+
+fn inPath(name: []const u8) bool {
+    const path = std.posix.getenv("PATH") orelse return false;
+    var dirs = mem.tokenizeScalar(u8, path, std.fs.path.delimiter);
+    var buf: [std.fs.max_path_bytes]u8 = undefined;
+    while (dirs.next()) |dir| {
+        const file = std.fmt.bufPrint(&buf, "{s}{s}{s}", .{ dir, std.fs.path.sep_str, name }) catch continue;
+        if (std.fs.cwd().access(file, .{})) |_| {
+            return true;
+        } else |_| {}
+    }
+    return false;
+}
+
+/// Replaces `$NAME` and `${NAME}` with the values in `env`, unknown ones become empty like in shells.
+fn expandEnv(allocator: Allocator, text: []const u8, env: *const std.process.EnvMap) ![]const u8 {
+    var expanded = ArrayList(u8).init(allocator);
+    var i = @as(usize, 0);
+    while (i < text.len) {
+        if (text[i] != '$') {
+            try expanded.append(text[i]);
+            i += 1;
+            continue;
+        }
+        const braced = i + 1 < text.len and text[i + 1] == '{';
+        const start = if (braced) i + 2 else i + 1;
+        var end = start;
+        while (end < text.len and (std.ascii.isAlphanumeric(text[end]) or text[end] == '_')) {
+            end += 1;
+        }
+        if (end == start or (braced and (end >= text.len or text[end] != '}'))) {
+            // not a variable
+            try expanded.append('$');
+            i += 1;
+            continue;
+        }
+        try expanded.appendSlice(env.get(text[start..end]) orelse "");
+        i = if (braced) end + 1 else end;
+    }
+    return expanded.toOwnedSlice();
+}
+
+/// Spawning a missing or non executable command fails with a cryptic error, so the gradle command
+/// is checked upfront. The wrapper may lose its execute bit when it is copied around, which is fixed here.
+fn checkCommand(allocator: Allocator, dir_path: ?[]const u8, program: []const u8) !void {
+    if (mem.indexOfScalar(u8, program, '/') == null and mem.indexOfScalar(u8, program, std.fs.path.sep) == null) {
+        if (!inPath(program)) {
+            fatalWith(.usage, "{s} not found in PATH; run from the repo root or set GRADLE_CMD", .{program});
+        }
+        return;
+    }
+    const base = dir_path orelse try std.process.getCwdAlloc(allocator);
+    const location = try std.fs.path.resolve(allocator, &[_][]const u8{ base, program });
+    const file = std.fs.cwd().openFile(location, .{}) catch |e| switch (e) {
+        error.FileNotFound => fatalWith(.usage, "{s} not found at {s}; run from the repo root or set GRADLE_CMD", .{ std.fs.path.basename(program), location }),
+        else => return e,
+    };
+    defer file.close();
+    if (builtin.os.tag == .windows) return;
+    const mode = (try file.stat()).mode;
+    if (mode & 0o111 != 0) return;
+    if (!mem.eql(u8, std.fs.path.basename(program), "gradlew")) {
+        fatalWith(.usage, "{s} isn't executable; make it executable or set GRADLE_CMD", .{location});
+    }
+    info("Make {s} executable", .{location});
+    try file.chmod(mode | 0o111);
+}
+
+/// The task of each project like `:app:lint`, so no aggregate task of the root project is needed.
+fn projectTasks(allocator: Allocator, projects: []const Projects.Entry, task: []const u8, commands: []const []const u8) ![]const []const u8 {
+    var tasks = try ArrayList([]const u8).initCapacity(allocator, projects.len + commands.len);
+    for (projects) |p| {
+        const task_path = if (p.name.len == 0) try std.fmt.allocPrint(allocator, ":{s}", .{task}) else try std.fmt.allocPrint(allocator, ":{s}:{s}", .{ p.name, task });
+        tasks.appendAssumeCapacity(task_path);
+    }
+    tasks.appendSliceAssumeCapacity(commands);
+    return tasks.toOwnedSlice();
+}
+
+/// Splits the projects into batches of about `size` projects, they are reordered so a project and the
+/// picked ones it depends on stay in one batch, gradle can't find a dependency in another batch.
+fn splitBatches(allocator: Allocator, projects: []Projects.Entry, size: usize, cache: ?*DependencyCache) ![]const []Projects.Entry {
+    var batches = ArrayList([]Projects.Entry).init(allocator);
+    if (projects.len <= size) {
+        try batches.append(projects);
+        return batches.toOwnedSlice();
+    }
+    // the projects depending on each other share the smallest index of them as their root
+    const parents = try allocator.alloc(usize, projects.len);
+    for (parents, 0..) |*parent, i| {
+        parent.* = i;
+    }
+    var indexes = StringHashMap(usize).init(allocator);
+    for (projects, 0..) |p, i| {
+        try indexes.put(p.name, i);
+    }
+    for (projects, 0..) |p, i| {
+        for (try p.readDependencies(allocator, cache)) |name| {
+            const found = if (mem.startsWith(u8, name, accessor_prefix)) dependencyIndex(projects, name) else indexes.get(name);
+            const j = found orelse continue;
+            const a = findRoot(parents, i);
+            const b = findRoot(parents, j);
+            parents[@max(a, b)] = @min(a, b);
+        }
+    }
+
+    const unordered = try allocator.dupe(Projects.Entry, projects);
+    var start = @as(usize, 0);
+    var next = @as(usize, 0);
+    for (0..projects.len) |i| {
+        if (findRoot(parents, i) != i) continue;
+        var count = @as(usize, 0);
+        for (i..projects.len) |j| {
+            if (findRoot(parents, j) == i) count += 1;
+        }
+        if (count > size) {
+            warn("{} projects depending on {s} are kept in one batch, more than {}", .{ count, unordered[i].name, size });
+        }
+        if (next > start and next - start + count > size) {
+            try batches.append(projects[start..next]);
+            start = next;
+        }
+        for (i..projects.len) |j| {
+            if (findRoot(parents, j) == i) {
+                projects[next] = unordered[j];
+                next += 1;
+            }
+        }
+    }
+    try batches.append(projects[start..next]);
+    return batches.toOwnedSlice();
+}
+
+fn findRoot(parents: []usize, index: usize) usize {
+    var i = index;
+    while (parents[i] != i) {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    return i;
+}
+
+/// Concurrent batches can't share one settings file, e.g. build.settings.2.gradle.kts
+fn batchSettingsFile(allocator: Allocator, settings_file: []const u8, index: usize) ![]const u8 {
+    const ext = if (mem.endsWith(u8, settings_file, ".gradle.kts")) ".gradle.kts" else if (mem.endsWith(u8, settings_file, ".gradle")) ".gradle" else "";
+    return std.fmt.allocPrint(allocator, "{s}.{}{s}", .{ settings_file[0 .. settings_file.len - ext.len], index, ext });
+}
 const Projects = struct {
     allocator: Allocator,
     entries: [@intFromEnum(State.Denied) + 1]ArrayList(Entry) = undefined,
+    cache: ?*DependencyCache = null,
+    skip_dirs: []const []const u8 = &default_skip_dirs,
+    name_rules: []const NameRule = &default_name_rules,
+    /// The files `denyUnchanged` found changed.
+    changed_files: []const []const u8 = &[_][]const u8{},
+    progress: std.Progress.Node = std.Progress.Node.none,
+    /// Set by `rebaseOnGradleRoots`, a dependency is then looked up in the gradle root of its dependent only.
+    rebased: bool = false,
 
     const Entry = struct {
         name: [:0]const u8,
         path: []const u8,
         root: []const u8,
         is_build_file_kts: bool,
+        reason: Reason = .all,
+
+        fn lessThan(_: void, a: Entry, b: Entry) bool {
+            return mem.lessThan(u8, a.name, b.name);
+        }
+
+        /// Names of the local projects the build file depends on, without the leading `:`.
+        fn readDependencies(self: Entry, allocator: Allocator, cache: ?*DependencyCache) ![]const []const u8 {
+            const file_name = try mem.concat(allocator, u8, &[_][]const u8{ self.root, std.fs.path.sep_str, self.path, std.fs.path.sep_str, if (self.is_build_file_kts) "build.gradle.kts" else "build.gradle" });
+            debug("build file: {s}", .{file_name});
+            const file = std.fs.openFileAbsolute(file_name, .{}) catch fatal("Can't open file: {s}", .{file_name});
+            defer file.close();
+            const mtime = (try file.stat()).mtime;
+            if (cache) |c| {
+                if (c.get(file_name, mtime)) |dependencies| {
+                    return dependencies;
+                }
+            }
+            const content = try std.fs.File.readToEndAlloc(file, allocator, @as(usize, 100_000_000));
+            const dependencies = try parseDependencies(allocator, content);
+            if (cache) |c| {
+                try c.put(file_name, mtime, dependencies);
+            }
+            return dependencies;
+        }
+    };
+    /// Why an entry is picked, the last step moving it decides.
+    const Reason = union(enum) {
+        all,
+        regexp: []const u8,
+        changed: []const u8,
+        dependency: []const u8,
+        forced: []const u8,
     };
     const State = enum(u2) {
         Added,
@@ -240,7 +1106,12 @@ const Projects = struct {
     }
 
     pub fn scan(self: *@This(), root: []const u8, max_depth: usize) !void {
-        debug("Start scanning {s}", .{root});
+        return self.scanDir(root, null, max_depth);
+    }
+
+    /// Scans the dir `top` of the root only if it is given, the projects are named from the root still.
+    fn scanDir(self: *@This(), root: []const u8, top: ?[]const u8, max_depth: usize) !void {
+        debug("Start scanning {s}/{s}", .{ root, top orelse "" });
         var projects = &self.entries[@intFromEnum(State.Added)];
         var names = [_][]const u8{""} ** (max_depth_allowed * 2);
         var dir_stack: [max_depth_allowed + 1]std.fs.Dir = undefined;
@@ -249,6 +1120,13 @@ const Projects = struct {
         iter_stack[0] = (&dir_stack[0]).iterate();
         var sp = @as(usize, 0);
         debug("Enter {s}", .{root});
+        if (top) |name| {
+            names[0] = name;
+            dir_stack[1] = try (&dir_stack[0]).openDir(name, .{ .iterate = true });
+            iter_stack[1] = (&dir_stack[1]).iterate();
+            sp = 1;
+        }
+        const bottom = sp;
         while (sp >= 0) {
             const entry = (&iter_stack[sp]).next() catch |e| blk: {
                 warn("Failed to iterate dir {}", .{e});
@@ -267,10 +1145,7 @@ const Projects = struct {
                     while (i < name_index) : (i += 2) {
                         names[i] = ":";
                     }
-                    if (name_index > 0 and mem.eql(u8, names[name_index], "android") or mem.eql(u8, names[name_index], "domain")) {
-                        names[name_index - 1] = "-";
-                    }
-                    const p_name = try mem.concatWithSentinel(self.allocator, u8, names[0 .. name_index + 1], 0);
+                    const p_name = try self.projectName(names[0 .. name_index + 1]);
                     const p = Entry{
                         .name = p_name,
                         .path = path,
@@ -278,9 +1153,10 @@ const Projects = struct {
                         .is_build_file_kts = mem.endsWith(u8, name, "kts"),
                     };
                     debug("Found project {s} at {s}/{s}, added", .{ p_name, root, path });
+                    self.progress.completeOne();
                     try projects.append(p);
                     // entry = null; // if not support nested projects, please uncomment
-                } else if (f.kind == .directory and sp < max_depth and !mem.startsWith(u8, name, ".")) {
+                } else if (f.kind == .directory and sp < max_depth and !mem.startsWith(u8, name, ".") and !self.isSkipped(name)) {
                     debug("Found {s}", .{name});
                     names[sp * 2] = name;
                     const depth = sp + 1;
@@ -294,7 +1170,7 @@ const Projects = struct {
             if (entry == null) {
                 const cur = sp;
                 defer _ = &dir_stack[cur].close();
-                if (sp == 0) {
+                if (sp == bottom) {
                     debug("Leave {s}", .{root});
                     break;
                 }
@@ -302,24 +1178,175 @@ const Projects = struct {
                 debug("Back to {s}", .{names[sp * 2]});
             }
         }
+        if (bottom > 0) {
+            dir_stack[0].close();
+        }
         debug("Finish scanning", .{});
     }
 
-    pub fn pick(self: *@This(), regexp: [:0]const u8) !void {
-        return self.move(regexp, .Added, .Picked);
+    /// Scans the dirs at the top of the roots on a few threads, the projects found are added in the order
+    /// of the roots and their dirs, like they are scanned one by one.
+    pub fn scanAll(self: *@This(), roots: []const []const u8, max_depth: usize) !void {
+        var thread_safe = std.heap.ThreadSafeAllocator{ .child_allocator = self.allocator };
+        var tasks = ArrayList(ScanTask).init(self.allocator);
+        const nodes = try self.allocator.alloc(std.Progress.Node, roots.len);
+        for (roots, nodes) |root, *node| {
+            node.* = self.progress.start(std.fs.path.basename(root), 0);
+            var dir = std.fs.openDirAbsolute(root, .{ .iterate = true }) catch fatal("Can't open directory: {s}", .{root});
+            defer dir.close();
+            var iter = dir.iterate();
+            while (iter.next() catch |e| blk: {
+                warn("Failed to iterate dir {}", .{e});
+                break :blk null;
+            }) |f| {
+                if (f.kind != .directory or mem.startsWith(u8, f.name, ".") or self.isSkipped(f.name)) continue;
+                var scanner = Projects.init(thread_safe.allocator());
+                scanner.skip_dirs = self.skip_dirs;
+                scanner.name_rules = self.name_rules;
+                scanner.progress = node.*;
+                try tasks.append(.{ .root = root, .top = try self.allocator.dupe(u8, f.name), .scanner = scanner });
+            }
+        }
+
+        var next = std.atomic.Value(usize).init(0);
+        var workers: [max_jobs]std.Thread = undefined;
+        const threads = @min(std.Thread.getCpuCount() catch 1, tasks.items.len, max_jobs);
+        var count = @as(usize, 0);
+        // this thread is one of them
+        while (count + 1 < threads) : (count += 1) {
+            workers[count] = std.Thread.spawn(.{}, scanTasks, .{ tasks.items, &next, max_depth }) catch |e| {
+                warn("Can't scan in more threads: {}", .{e});
+                break;
+            };
+        }
+        scanTasks(tasks.items, &next, max_depth);
+        for (workers[0..count]) |worker| {
+            worker.join();
+        }
+        for (nodes) |node| {
+            node.end();
+        }
+        for (tasks.items) |task| {
+            try self.entries[@intFromEnum(State.Added)].appendSlice(task.scanner.entries[@intFromEnum(State.Added)].items);
+        }
     }
 
-    pub fn pickAll(self: *@This()) !void {
-        info("Move all .Added to .Picked", .{});
-        try self.entries[@intFromEnum(State.Picked)].appendSlice(try self.entries[@intFromEnum(State.Added)].toOwnedSlice());
+    /// A dir at the top of a root, scanned into its own projects.
+    const ScanTask = struct {
+        root: []const u8,
+        top: []const u8,
+        scanner: Projects,
+    };
+
+    fn scanTasks(tasks: []ScanTask, next: *std.atomic.Value(usize), max_depth: usize) void {
+        while (true) {
+            const index = next.fetchAdd(1, .monotonic);
+            if (index >= tasks.len) return;
+            const task = &tasks[index];
+            task.scanner.scanDir(task.root, task.top, max_depth) catch |e| fatal("Scan {s}/{s} failed: {}", .{ task.root, task.top, e });
+        }
     }
 
-    pub fn deny(self: *@This(), regexp: [:0]const u8) !void {
-        return self.move(regexp, .Picked, .Denied);
+    /// Joins the path parts of a project, then rewrites it with the first name rule matching its end.
+    fn projectName(self: *@This(), parts: []const []const u8) ![:0]const u8 {
+        return self.applyNameRules(try mem.concat(self.allocator, u8, parts));
     }
 
-    pub fn filter(self: *@This(), script: []const u8) !void {
-        info("Move projects based on filter {s}", .{script});
+    fn applyNameRules(self: *@This(), name: []const u8) ![:0]const u8 {
+        for (self.name_rules) |rule| {
+            if (mem.endsWith(u8, name, rule.from)) {
+                return mem.concatWithSentinel(self.allocator, u8, &[_][]const u8{ name[0 .. name.len - rule.from.len], rule.to }, 0);
+            }
+        }
+        return self.allocator.dupeZ(u8, name);
+    }
+
+    fn isSkipped(self: *@This(), dir_name: []const u8) bool {
+        for (self.skip_dirs) |skip| {
+            if (mem.eql(u8, skip, dir_name)) {
+                debug("Skip dir {s}", .{dir_name});
+                return true;
+            }
+        }
+        return false;
+    }
+
+    /// Makes the names and paths relative to the nearest dir having a gradle wrapper, which is how the
+    /// gradle build there and its build files name them, the root project of that build is named "".
+    pub fn rebaseOnGradleRoots(self: *@This()) !void {
+        for (&self.entries) |*entries| {
+            for (entries.items) |*entry| {
+                const dir = try gradleRoot(self.allocator, entry.*) orelse continue;
+                const project_dir = try std.fs.path.join(self.allocator, &[_][]const u8{ entry.root, entry.path });
+                const path = try std.fs.path.relative(self.allocator, dir, project_dir);
+                const name = try self.allocator.dupe(u8, path);
+                mem.replaceScalar(u8, name, std.fs.path.sep, ':');
+                entry.name = if (path.len == 0) "" else try self.applyNameRules(name);
+                entry.root = dir;
+                entry.path = path;
+            }
+        }
+        self.rebased = true;
+    }
+
+    pub fn pick(self: *@This(), regexp: [:0]const u8) !void {
+        return self.move(regexp, .Added, .Picked, .{ .regexp = regexp });
+    }
+
+    pub fn pickAll(self: *@This()) !void {
+        info("Move all .Added to .Picked", .{});
+        try self.entries[@intFromEnum(State.Picked)].appendSlice(try self.entries[@intFromEnum(State.Added)].toOwnedSlice());
+    }
+
+    pub fn deny(self: *@This(), regexp: [:0]const u8) !void {
+        return self.move(regexp, .Picked, .Denied, null);
+    }
+
+    /// Unlike `deny`, the names are compared as they are, so they needn't be escaped.
+    pub fn denyNames(self: *@This(), names: []const []const u8) !void {
+        var from_list = &self.entries[@intFromEnum(State.Picked)];
+        var to_list = &self.entries[@intFromEnum(State.Denied)];
+        var i = @as(usize, 0);
+        next: while (i < from_list.items.len) {
+            for (names) |name| {
+                if (mem.eql(u8, from_list.items[i].name, name)) {
+                    info("Move {s} from .Picked to .Denied", .{name});
+                    try to_list.append(from_list.swapRemove(i));
+                    continue :next;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Picks the projects again whatever state they are in, a line having regexp characters is taken as
+    /// a pattern, or it is compared with the names as it is.
+    pub fn force(self: *@This(), lines: []const [:0]const u8) !void {
+        for (lines) |line| {
+            if (mem.indexOfAny(u8, line, "^$*+?()[]{}|\\") != null) {
+                try self.move(line, .Added, .Picked, .{ .forced = line });
+                try self.move(line, .Denied, .Picked, .{ .forced = line });
+                continue;
+            }
+            for ([_]State{ .Added, .Denied }) |state| {
+                var from_list = &self.entries[@intFromEnum(state)];
+                var i = @as(usize, 0);
+                while (i < from_list.items.len) {
+                    if (mem.eql(u8, from_list.items[i].name, line)) {
+                        info("Move {s} from {} to .Picked", .{ line, state });
+                        var entry = from_list.swapRemove(i);
+                        entry.reason = .{ .forced = line };
+                        try self.entries[@intFromEnum(State.Picked)].append(entry);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn filter(self: *@This(), script: []const u8) !void {
+        info("Move projects based on filter {s}", .{script});
         var from_list = &self.entries[@intFromEnum(State.Picked)];
         var to_list = &self.entries[@intFromEnum(State.Denied)];
         var i = @as(usize, 0);
@@ -342,35 +1369,55 @@ const Projects = struct {
         }
     }
 
-    pub fn denyUnchanged(self: *@This(), root: []const u8, since_commit: []const u8, max_depth: usize) !void {
-        info("Move projects based on changes since commit {s}", .{since_commit});
+    pub fn denyUnchanged(self: *@This(), root: []const u8, since_commit: []const u8, max_depth: usize, target: DiffTarget) !void {
+        info("Move projects based on changes of {s} since commit {s}", .{ @tagName(target), since_commit });
         var arena = std.heap.ArenaAllocator.init(std.heap.page_allocator);
         defer arena.deinit();
         const allocator = arena.allocator();
 
-        if (exec(allocator, &[_][]const u8{
-            "git", "diff", "--name-only", since_commit,
-        }, root)) |changes| {
+        // a renamed file changes the project it leaves too, which only the old path tells
+        const workdir_diff = [_][]const u8{ "git", "diff", "--name-only", "--no-renames", since_commit };
+        const index_diff = [_][]const u8{ "git", "diff", "--name-only", "--no-renames", "--cached", since_commit };
+        const head_diff = [_][]const u8{ "git", "diff", "--name-only", "--no-renames", since_commit, "HEAD" };
+        const diff: []const []const u8 = switch (target) {
+            .workdir => &workdir_diff,
+            .index => &index_diff,
+            .head => &head_diff,
+        };
+        if (exec(allocator, diff, root)) |changes| {
+            const untracked = if (target != .workdir) "" else exec(allocator, &[_][]const u8{
+                "git", "ls-files", "-o", "--exclude-standard", "--modified",
+            }, root) catch "";
             var dirs = StringHashMap(void).init(allocator);
             try cacheDirs(changes, max_depth, &dirs);
-            try cacheDirs(exec(allocator, &[_][]const u8{
-                "git", "ls-files", "-o", "--exclude-standard", "--modified",
-            }, root) catch "", max_depth, &dirs);
+            try cacheDirs(untracked, max_depth, &dirs);
+            var changed_files = ArrayList([]const u8).init(self.allocator);
+            for ([_][]const u8{ changes, untracked }) |files| {
+                var lines = mem.tokenize(u8, files, "\n");
+                while (lines.next()) |line| {
+                    try changed_files.append(try self.allocator.dupe(u8, line));
+                }
+            }
+            self.changed_files = try changed_files.toOwnedSlice();
 
             var from_list = &self.entries[@intFromEnum(State.Picked)];
             var to_list = &self.entries[@intFromEnum(State.Denied)];
             var i = @as(usize, 0);
             while (i < from_list.items.len) {
-                debug("checking {s}", .{from_list.items[i].path});
-                if (!dirs.contains(from_list.items[i].path)) {
-                    info("Move {s} from .Picked to .Denied", .{from_list.items[i].path});
+                const entry = from_list.items[i];
+                // the changed files are relative to the git root
+                const path = if (self.rebased) try std.fs.path.relative(allocator, root, try std.fs.path.join(allocator, &[_][]const u8{ entry.root, entry.path })) else entry.path;
+                debug("checking {s}", .{path});
+                if (!dirs.contains(path)) {
+                    info("Move {s} from .Picked to .Denied", .{path});
                     try to_list.append(from_list.swapRemove(i));
                 } else {
+                    from_list.items[i].reason = .{ .changed = since_commit };
                     i += 1;
                 }
             }
         } else |e| {
-            fatal("Can't get git diff, {}", .{e});
+            fatalWith(.git, "Can't get git diff, {}", .{e});
         }
     }
 
@@ -379,54 +1426,40 @@ const Projects = struct {
         var arena = std.heap.ArenaAllocator.init(std.heap.page_allocator);
         defer arena.deinit();
         const allocator = arena.allocator();
+        const progress = self.progress.start("Read dependencies", 0);
+        defer progress.end();
         const from_lists = [_]*ArrayList(Entry){ &self.entries[@intFromEnum(State.Added)], &self.entries[@intFromEnum(State.Denied)] };
         var to_list = &self.entries[@intFromEnum(State.Picked)];
         var i = @as(usize, 0);
         while (i < to_list.items.len) {
-            const project = &to_list.items[i];
+            const project = to_list.items[i];
             debug("scan {s}", .{project.name});
             i += 1;
-            const file_name = try mem.concat(allocator, u8, &[_][]const u8{ project.root, std.fs.path.sep_str, project.path, std.fs.path.sep_str, if (project.is_build_file_kts) "build.gradle.kts" else "build.gradle" });
-            debug("build file: {s}", .{file_name});
-            var file = std.fs.openFileAbsolute(file_name, .{}) catch fatal("Can't open file: {s}", .{file_name});
-            defer file.close();
-            const content = try std.fs.File.readToEndAlloc(file, allocator, @as(usize, 100_000_000));
-            var lines = mem.tokenize(u8, content, "\n");
-            outer: while (lines.next()) |line| {
-                if (mem.indexOf(u8, line, "project")) |index| {
-                    debug("Found project in line: {s}", .{line});
-                    if (mem.indexOf(u8, line[0..index], "//")) |_| {
-                        debug("Line is commented {s}", .{line});
-                        continue :outer;
-                    }
-                    if (mem.indexOfPos(u8, line, index + 7, ":")) |start| {
-                        if (mem.indexOfNone(u8, line[index + 7 .. start], " \"'(")) |_| {
-                            debug("Not a correct format: {s}", .{line[index + 7 ..]});
-                            continue :outer;
-                        }
-                        if (mem.indexOfAnyPos(u8, line, start, "'\"")) |end| {
-                            const name = line[start + 1 .. end];
-                            debug("Detect a local project: {s}", .{name});
-                            for (from_lists) |from_list| {
-                                var j = @as(usize, 0);
-                                while (j < from_list.items.len) {
-                                    if (mem.eql(u8, from_list.items[j].name, name)) {
-                                        info("Found local project dependency not picked: {s}, import it", .{name});
-                                        try to_list.append(from_list.swapRemove(j));
-                                        continue :outer;
-                                    }
-                                    j += 1;
-                                }
-                            }
+            progress.completeOne();
+            next: for (try project.readDependencies(allocator, self.cache)) |name| {
+                for (from_lists) |from_list| {
+                    var j = @as(usize, 0);
+                    while (j < from_list.items.len) {
+                        const same_root = !self.rebased or mem.eql(u8, from_list.items[j].root, project.root);
+                        if (same_root and isDependency(from_list.items[j].name, name)) {
+                            info("Found local project dependency not picked: {s}, import it", .{name});
+                            var dependency = from_list.swapRemove(j);
+                            dependency.reason = .{ .dependency = project.name };
+                            try to_list.append(dependency);
+                            continue :next;
                         }
-                    } else {
-                        debug("Not a correct format: {s}", .{line});
+                        j += 1;
                     }
                 }
             }
         }
     }
 
+    /// Moving projects between states reorders them, sorting keeps the settings files and listings stable.
+    pub fn sortPicked(self: *@This()) void {
+        mem.sort(Entry, self.entries[@intFromEnum(State.Picked)].items, {}, Entry.lessThan);
+    }
+
     inline fn cacheDirs(files: []const u8, max_depth: usize, cache: *StringHashMap(void)) !void {
         var lines = mem.tokenize(u8, files, "\n");
         while (lines.next()) |line| {
@@ -442,7 +1475,8 @@ const Projects = struct {
         }
     }
 
-    fn move(self: *@This(), pattern: [:0]const u8, from: State, to: State) !void {
+    /// The moved entries are given the reason if any.
+    fn move(self: *@This(), pattern: [:0]const u8, from: State, to: State, reason: ?Reason) !void {
         info("Move projects state based on the regexp {s}", .{pattern});
         var arena = std.heap.ArenaAllocator.init(std.heap.c_allocator);
         defer arena.deinit();
@@ -453,7 +1487,7 @@ const Projects = struct {
         mem.copyForwards(u8, buf, pattern);
         buf[pattern.len] = 0;
         if (re.compile(buf_ptr) != 0) {
-            fatal("Invalid regex '{s}'", .{pattern});
+            fatalWith(.usage, "Invalid regex '{s}'", .{pattern});
         }
         var from_list = &self.entries[@intFromEnum(from)];
         var to_list = &self.entries[@intFromEnum(to)];
@@ -465,7 +1499,11 @@ const Projects = struct {
             const ret = re.isMatch(buf_ptr);
             if (ret == 0) {
                 info("Move {s} from {} to {}", .{ name, from, to });
-                try to_list.append(from_list.swapRemove(i));
+                var entry = from_list.swapRemove(i);
+                if (reason) |r| {
+                    entry.reason = r;
+                }
+                try to_list.append(entry);
             } else {
                 debug("Checking project {s}: return {}", .{ buf_ptr, ret });
                 i += 1;
@@ -474,6 +1512,615 @@ const Projects = struct {
     }
 };
 
+/// Names of the `project(":name")` and `projects.name` dependencies, a declaration can be wrapped
+/// into many lines, and the commented ones are skipped.
+fn parseDependencies(allocator: Allocator, content: []const u8) ![]const []const u8 {
+    const code = try stripComments(allocator, content);
+    var names = ArrayList([]const u8).init(allocator);
+    var pos = @as(usize, 0);
+    while (mem.indexOfPos(u8, code, pos, "project")) |index| {
+        pos = index + "project".len;
+        if (index > 0 and (std.ascii.isAlphanumeric(code[index - 1]) or code[index - 1] == '_')) {
+            continue;
+        }
+        if (mem.startsWith(u8, code[index..], accessor_prefix)) {
+            var end = index + accessor_prefix.len;
+            while (end < code.len and isAccessorChar(code[end])) : (end += 1) {}
+            const accessor = mem.trimRight(u8, code[index + accessor_prefix.len .. end], ".");
+            pos = end;
+            if (accessor.len > 0) {
+                // it is matched with the project names later, many names have the same accessor
+                debug("Detect a local project by accessor {s}", .{accessor});
+                try names.append(code[index .. index + accessor_prefix.len + accessor.len]);
+            }
+            continue;
+        }
+        const start = mem.indexOfNonePos(u8, code, pos, " \t\r\n\"'(") orelse break;
+        if (code[start] != ':') {
+            debug("Not a correct format: {s}", .{code[index..@min(code.len, start + 1)]});
+            continue;
+        }
+        const end = mem.indexOfAnyPos(u8, code, start, "'\"") orelse break;
+        const name = code[start + 1 .. end];
+        debug("Detect a local project: {s}", .{name});
+        try names.append(name);
+        pos = end;
+    }
+    return try names.toOwnedSlice();
+}
+
+/// Blanks out the `//` and `/* */` comments outside of string literals, line breaks are kept.
+fn stripComments(allocator: Allocator, content: []const u8) ![]u8 {
+    const code = try allocator.dupe(u8, content);
+    var quote: ?u8 = null;
+    var i = @as(usize, 0);
+    while (i < code.len) : (i += 1) {
+        const c = code[i];
+        if (quote) |q| {
+            if (c == '\\') {
+                i += 1;
+            } else if (c == q or c == '\n') {
+                quote = null;
+            }
+        } else if (c == '"' or c == '\'') {
+            quote = c;
+        } else if (mem.startsWith(u8, code[i..], "//")) {
+            const end = mem.indexOfScalarPos(u8, code, i, '\n') orelse code.len;
+            @memset(code[i..end], ' ');
+            i = end;
+        } else if (mem.startsWith(u8, code[i..], "/*")) {
+            const end = if (mem.indexOfPos(u8, code, i + 2, "*/")) |e| e + 2 else code.len;
+            for (code[i..end]) |*b| {
+                if (b.* != '\n') b.* = ' ';
+            }
+            i = end - 1;
+        }
+    }
+    return code;
+}
+
+/// Parsed dependencies of the build files, stored in the git dir between runs, an entry is
+/// dropped once the modification time of its build file changes.
+const DependencyCache = struct {
+    allocator: Allocator,
+    path: []const u8,
+    entries: StringHashMap(Cached),
+    dirty: bool = false,
+    hits: usize = 0,
+    misses: usize = 0,
+
+    const file_name = "abt-cache.json";
+    /// Bumped once `parseDependencies` finds other names in the same build file, so the cached ones are dropped.
+    const version = 2;
+    const Cached = struct { mtime: i128, dependencies: []const []const u8 };
+    const Stored = struct { file: []const u8, mtime: i128, dependencies: []const []const u8 };
+    const Content = struct { version: u32, entries: []const Stored };
+
+    fn load(allocator: Allocator, path: []const u8) !DependencyCache {
+        var self = DependencyCache{
+            .allocator = allocator,
+            .path = path,
+            .entries = StringHashMap(Cached).init(allocator),
+        };
+        const content = std.fs.cwd().readFileAlloc(allocator, self.path, 100_000_000) catch |e| {
+            debug("No dependency cache loaded from {s}: {}", .{ self.path, e });
+            return self;
+        };
+        // the caches of the first versions are arrays without a version
+        const versioned = std.json.parseFromSliceLeaky(struct { version: u32 }, allocator, content, .{ .ignore_unknown_fields = true }) catch null;
+        if (versioned == null or versioned.?.version != version) {
+            info("Drop the dependency cache {s} of another abt version", .{self.path});
+            std.fs.deleteFileAbsolute(self.path) catch |e| warn("Can't delete the dependency cache {s}: {}", .{ self.path, e });
+            return self;
+        }
+        const stored = std.json.parseFromSliceLeaky(Content, allocator, content, .{ .ignore_unknown_fields = true }) catch |e| {
+            warn("Ignore the broken dependency cache {s}: {}", .{ self.path, e });
+            return self;
+        };
+        for (stored.entries) |entry| {
+            try self.entries.put(entry.file, .{ .mtime = entry.mtime, .dependencies = entry.dependencies });
+        }
+        debug("Loaded {} cached build files from {s}", .{ stored.entries.len, self.path });
+        return self;
+    }
+
+    fn get(self: *DependencyCache, file: []const u8, mtime: i128) ?[]const []const u8 {
+        if (self.entries.get(file)) |cached| {
+            if (cached.mtime == mtime) {
+                self.hits += 1;
+                return cached.dependencies;
+            }
+        }
+        self.misses += 1;
+        return null;
+    }
+
+    /// Copies everything, the caller may free them with its own arena.
+    fn put(self: *DependencyCache, file: []const u8, mtime: i128, dependencies: []const []const u8) !void {
+        const copies = try self.allocator.alloc([]const u8, dependencies.len);
+        for (dependencies, copies) |dependency, *copy| {
+            copy.* = try self.allocator.dupe(u8, dependency);
+        }
+        try self.entries.put(try self.allocator.dupe(u8, file), .{ .mtime = mtime, .dependencies = copies });
+        self.dirty = true;
+    }
+
+    fn save(self: *DependencyCache) void {
+        debug("Dependency cache hits: {}, misses: {}", .{ self.hits, self.misses });
+        if (!self.dirty) return;
+        self.write() catch |e| {
+            warn("Can't write dependency cache {s}: {}", .{ self.path, e });
+            return;
+        };
+        self.dirty = false;
+    }
+
+    fn write(self: *DependencyCache) !void {
+        var stored = try ArrayList(Stored).initCapacity(self.allocator, self.entries.count());
+        var iter = self.entries.iterator();
+        while (iter.next()) |entry| {
+            stored.appendAssumeCapacity(.{ .file = entry.key_ptr.*, .mtime = entry.value_ptr.mtime, .dependencies = entry.value_ptr.dependencies });
+        }
+        const file = try std.fs.createFileAbsolute(self.path, .{ .truncate = true });
+        defer file.close();
+        var buffered = io.bufferedWriter(file.writer());
+        try std.json.stringify(Content{ .version = version, .entries = stored.items }, .{}, buffered.writer());
+        try buffered.flush();
+    }
+};
+
+/// The projects picked by the last run, stored next to the dependency cache.
+const LastSelection = struct {
+    head: []const u8,
+    key: u64,
+    compare_commit: ?[]const u8 = null,
+    changed_files: []const []const u8 = &[_][]const u8{},
+    projects: []const Project,
+
+    const file_name = "abt-last-selection.json";
+    /// The reason is stored as its tag and the value it carries, if any.
+    const Project = struct { name: []const u8, path: []const u8, root: []const u8, is_build_file_kts: bool, reason: []const u8 = "all", detail: []const u8 = "" };
+    /// What `select` found, reused as is by `--explain`, `--status` and `--summary`.
+    const Selection = struct {
+        compare_commit: ?[]const u8,
+        changed_files: []const []const u8,
+        entries: []const Projects.Entry,
+    };
+
+    /// Null if the selection is stale, i.e. HEAD moved or the selecting options changed.
+    fn load(allocator: Allocator, path: []const u8, head: []const u8, key: u64) !?Selection {
+        const content = std.fs.cwd().readFileAlloc(allocator, path, 100_000_000) catch |e| {
+            info("No last selection loaded from {s}: {}", .{ path, e });
+            return null;
+        };
+        const last = std.json.parseFromSliceLeaky(LastSelection, allocator, content, .{ .ignore_unknown_fields = true }) catch |e| {
+            warn("Ignore the broken last selection {s}: {}", .{ path, e });
+            return null;
+        };
+        if (!mem.eql(u8, last.head, head)) {
+            info("HEAD moved from {s} since the last selection, select again", .{last.head});
+            return null;
+        }
+        if (last.key != key) {
+            info("The options or the uncommitted changes changed since the last selection, select again", .{});
+            return null;
+        }
+        const entries = try allocator.alloc(Projects.Entry, last.projects.len);
+        for (last.projects, entries) |p, *entry| {
+            const tag = std.meta.stringToEnum(std.meta.Tag(Projects.Reason), p.reason) orelse {
+                warn("Ignore the last selection {s} with an unknown reason {s}", .{ path, p.reason });
+                return null;
+            };
+            const reason: Projects.Reason = switch (tag) {
+                .all => .all,
+                .regexp => .{ .regexp = p.detail },
+                .changed => .{ .changed = p.detail },
+                .dependency => .{ .dependency = p.detail },
+                .forced => .{ .forced = p.detail },
+            };
+            entry.* = .{ .name = try allocator.dupeZ(u8, p.name), .path = p.path, .root = p.root, .is_build_file_kts = p.is_build_file_kts, .reason = reason };
+        }
+        return .{ .compare_commit = last.compare_commit, .changed_files = last.changed_files, .entries = entries };
+    }
+
+    fn save(allocator: Allocator, path: []const u8, head: []const u8, key: u64, selection: Selection) !void {
+        const stored = try allocator.alloc(Project, selection.entries.len);
+        for (selection.entries, stored) |p, *project| {
+            const detail = switch (p.reason) {
+                .all => "",
+                inline else => |value| value,
+            };
+            project.* = .{ .name = p.name, .path = p.path, .root = p.root, .is_build_file_kts = p.is_build_file_kts, .reason = @tagName(p.reason), .detail = detail };
+        }
+        const file = try std.fs.createFileAbsolute(path, .{ .truncate = true });
+        defer file.close();
+        var buffered = io.bufferedWriter(file.writer());
+        const last = LastSelection{ .head = head, .key = key, .compare_commit = selection.compare_commit, .changed_files = selection.changed_files, .projects = stored };
+        try std.json.stringify(last, .{}, buffered.writer());
+        try buffered.flush();
+    }
+};
+
+/// Changes with any option deciding which projects are picked.
+fn selectionKey(options: *const Options) u64 {
+    var hasher = std.hash.Wyhash.init(0);
+    const values = [_]?[]const u8{ options.since_commit, options.base_branch, options.regexp, options.invert_match, options.filter };
+    for (values) |value| {
+        if (value) |v| {
+            hasher.update(mem.asBytes(&v.len));
+            hasher.update(v);
+        } else {
+            hasher.update("null");
+        }
+    }
+    for ([_][]const []const u8{ options.excludes.items, options.skip_dirs.items }) |names| {
+        hasher.update(mem.asBytes(&names.len));
+        for (names) |name| {
+            hasher.update(mem.asBytes(&name.len));
+            hasher.update(name);
+        }
+    }
+    for (options.forced_projects.items) |line| {
+        hasher.update(line);
+        hasher.update("\n");
+    }
+    if (options.name_rules) |rules| {
+        for (rules.items) |rule| {
+            hasher.update(rule.from);
+            hasher.update("=");
+            hasher.update(rule.to);
+            hasher.update("\n");
+        }
+    }
+    // the includes aren't ordered
+    var includes = @as(u64, 0);
+    var iter = options.includes.keyIterator();
+    while (iter.next()) |path| {
+        includes +%= std.hash.Wyhash.hash(0, path.*);
+    }
+    hasher.update(mem.asBytes(&includes));
+    hasher.update(mem.asBytes(&options.max_depth));
+    hasher.update(&[_]u8{ @intFromBool(options.include_local_dependencies), @intFromBool(options.only_changed), @intFromEnum(options.diff_target), @intFromBool(options.per_gradle_root) });
+    return hasher.final();
+}
+
+const accessor_prefix = "projects.";
+
+fn isAccessorChar(c: u8) bool {
+    return std.ascii.isAlphanumeric(c) or c == '_' or c == '.';
+}
+
+/// Whether the project is the dependency, which is a name or a type-safe project accessor of gradle,
+/// `feature:foo-bar`, `feature:foo_bar` and `feature:fooBar` are all `projects.feature.fooBar`.
+fn isDependency(name: []const u8, dependency: []const u8) bool {
+    if (!mem.startsWith(u8, dependency, accessor_prefix)) return mem.eql(u8, name, dependency);
+    const accessor = dependency[accessor_prefix.len..];
+    var i = @as(usize, 0);
+    var upper = false;
+    for (name) |c| {
+        const expected = switch (c) {
+            '-', '_' => {
+                upper = true;
+                continue;
+            },
+            ':' => '.',
+            else => if (upper) std.ascii.toUpper(c) else c,
+        };
+        upper = false;
+        if (i >= accessor.len or accessor[i] != expected) return false;
+        i += 1;
+    }
+    return i == accessor.len;
+}
+
+/// The index of the project the dependency is, see `isDependency`.
+fn dependencyIndex(projects: []const Projects.Entry, dependency: []const u8) ?usize {
+    for (projects, 0..) |p, i| {
+        if (isDependency(p.name, dependency)) return i;
+    }
+    return null;
+}
+
+const GraphFormat = enum { dot, json };
+
+/// Writes every scanned project with its local project dependencies, whatever it is picked or not.
+fn graph(allocator: Allocator, projects: *Projects, output: []const u8, format: GraphFormat) !void {
+    const Node = struct { name: []const u8, dependencies: []const []const u8 };
+    var nodes = ArrayList(Node).init(allocator);
+    var all = ArrayList(Projects.Entry).init(allocator);
+    for (projects.entries) |entries| {
+        try all.appendSlice(entries.items);
+    }
+    for (all.items) |p| {
+        const dependencies = try allocator.dupe([]const u8, try p.readDependencies(allocator, projects.cache));
+        for (dependencies) |*dependency| {
+            if (mem.startsWith(u8, dependency.*, accessor_prefix)) {
+                if (dependencyIndex(all.items, dependency.*)) |i| dependency.* = all.items[i].name;
+            }
+        }
+        try nodes.append(.{ .name = p.name, .dependencies = dependencies });
+    }
+
+    const file = std.fs.cwd().createFile(output, .{ .truncate = true }) catch |e| {
+        fatal("Can't create file {s} {}", .{ output, e });
+    };
+    defer file.close();
+    var buffered = io.bufferedWriter(file.writer());
+    const writer = buffered.writer();
+    switch (format) {
+        .dot => {
+            try writer.writeAll("digraph projects {\n");
+            for (nodes.items) |node| {
+                try writer.print("  \"{s}\";\n", .{node.name});
+                for (node.dependencies) |dependency| {
+                    try writer.print("  \"{s}\" -> \"{s}\";\n", .{ node.name, dependency });
+                }
+            }
+            try writer.writeAll("}\n");
+        },
+        .json => {
+            try std.json.stringify(nodes.items, .{}, writer);
+            try writer.writeByte('\n');
+        },
+    }
+    try buffered.flush();
+    info("Dependency graph of {} projects written to {s}", .{ nodes.items.len, output });
+}
+
+const ListFormat = enum { json, names, paths };
+
+fn list(allocator: Allocator, projects: []Projects.Entry, format: ListFormat) !void {
+    const stdout = io.getStdOut().writer();
+    switch (format) {
+        .json => {
+            const Item = struct { name: []const u8, path: []const u8 };
+            const items = try allocator.alloc(Item, projects.len);
+            for (projects, items) |p, *item| {
+                item.* = .{ .name = p.name, .path = try std.fs.path.join(allocator, &[_][]const u8{ p.root, p.path }) };
+            }
+            try std.json.stringify(items, .{}, stdout);
+            try stdout.writeByte('\n');
+        },
+        .names => {
+            for (projects) |p| {
+                try stdout.print("{s}\n", .{p.name});
+            }
+        },
+        .paths => {
+            for (projects) |p| {
+                try stdout.print("{s}{s}{s}\n", .{ p.root, std.fs.path.sep_str, p.path });
+            }
+        },
+    }
+}
+
+/// Prints a table of the picked projects and why each one is picked.
+fn explain(writer: anytype, projects: []const Projects.Entry) !void {
+    const header = "PROJECT";
+    var width = header.len;
+    for (projects) |p| {
+        width = @max(width, p.name.len);
+    }
+    try writer.writeAll(header);
+    try writer.writeByteNTimes(' ', width - header.len + 2);
+    try writer.writeAll("REASON\n");
+    for (projects) |p| {
+        try writer.writeAll(p.name);
+        try writer.writeByteNTimes(' ', width - p.name.len + 2);
+        switch (p.reason) {
+            .all => try writer.writeAll("no regexp given\n"),
+            .regexp => |pattern| try writer.print("name matches {s}\n", .{pattern}),
+            .changed => |commit| try writer.print("changed since {s}\n", .{commit}),
+            .dependency => |name| try writer.print("dependency of {s}\n", .{name}),
+            .forced => |line| try writer.print("listed as {s} in the projects file\n", .{line}),
+        }
+    }
+}
+
+/// A read only view of what a build would do, nothing is written and gradle isn't run.
+fn printStatus(allocator: Allocator, vc_root: ?[]const u8, compare_commit: ?[]const u8, changed_files: []const []const u8, projects: []const Projects.Entry) !void {
+    var branch: ?[]const u8 = null;
+    var upstream: ?[]const u8 = null;
+    if (vc_root) |root| {
+        if (exec(allocator, &[_][]const u8{ "git", "rev-parse", "--abbrev-ref", "HEAD" }, root)) |name| {
+            branch = mem.trimRight(u8, name, "\n");
+            const ref = try std.fmt.allocPrint(allocator, "refs/heads/{s}", .{branch.?});
+            if (exec(allocator, &[_][]const u8{ "git", "for-each-ref", "--format=%(upstream:short)", ref }, root)) |name_of_upstream| {
+                const trimmed = mem.trimRight(u8, name_of_upstream, "\n");
+                if (trimmed.len > 0) upstream = trimmed;
+            } else |e| {
+                warn("Can't find the upstream of {s}: {}", .{ branch.?, e });
+            }
+        } else |e| {
+            warn("Can't find the current branch: {}", .{e});
+        }
+    }
+    var buffered = io.bufferedWriter(io.getStdOut().writer());
+    try writeStatus(buffered.writer(), branch, upstream, compare_commit, changed_files, projects);
+    try buffered.flush();
+}
+
+fn writeStatus(writer: anytype, branch: ?[]const u8, upstream: ?[]const u8, compare_commit: ?[]const u8, changed_files: []const []const u8, projects: []const Projects.Entry) !void {
+    try writer.print("Branch: {s}\n", .{branch orelse "none"});
+    try writer.print("Upstream: {s}\n", .{upstream orelse "none"});
+    if (compare_commit) |commit| {
+        try writer.print("Compare commit: {s}\n", .{commit});
+        try writer.print("Changed files: {}\n", .{changed_files.len});
+        for (changed_files) |file| {
+            try writer.print("  {s}\n", .{file});
+        }
+    } else {
+        try writer.writeAll("Compare commit: none, all projects are candidates\n");
+    }
+    try writer.print("Selected projects: {}\n", .{projects.len});
+    for (projects) |p| {
+        try writer.print("  {s}\n", .{p.name});
+    }
+}
+
+/// The lines of a projects file without the comments and blank lines.
+fn parseProjectsFile(allocator: Allocator, content: []const u8) ![]const [:0]const u8 {
+    var names = ArrayList([:0]const u8).init(allocator);
+    var lines = mem.tokenize(u8, content, "\n");
+    while (lines.next()) |raw| {
+        const comment = mem.indexOfScalar(u8, raw, '#') orelse raw.len;
+        const line = mem.trim(u8, raw[0..comment], " \t\r");
+        if (line.len > 0) {
+            try names.append(try allocator.dupeZ(u8, line));
+        }
+    }
+    return names.toOwnedSlice();
+}
+
+const config_file_name = ".abt.toml";
+
+/// Reads the defaults in `.abt.toml` of the git root if any, options parsed later override them.
+fn loadConfigFile(allocator: Allocator, root: []const u8, options: *Options) !void {
+    const path = try std.fs.path.join(allocator, &[_][]const u8{ root, config_file_name });
+    const file = std.fs.openFileAbsolute(path, .{}) catch |e| switch (e) {
+        error.FileNotFound => {
+            debug("No config file {s}", .{path});
+            return;
+        },
+        else => return e,
+    };
+    defer file.close();
+    info("Load config from {s}", .{path});
+    try applyConfig(allocator, try file.readToEndAlloc(allocator, 1024 * 1024), root, options);
+}
+
+/// Only a small subset of toml is supported: `key = value` lines where the value is a string,
+/// an integer, a boolean or a one line array of strings.
+fn applyConfig(allocator: Allocator, content: []const u8, root: []const u8, options: *Options) !void {
+    var lines = mem.tokenize(u8, content, "\n");
+    while (lines.next()) |raw| {
+        const line = mem.trim(u8, stripConfigComment(raw), " \t\r");
+        if (line.len == 0) continue;
+        const eq = mem.indexOfScalar(u8, line, '=') orelse fatalWith(.usage, "Invalid line in {s}: {s}", .{ config_file_name, raw });
+        const key = mem.trim(u8, line[0..eq], " \t");
+        const value = mem.trim(u8, line[eq + 1 ..], " \t");
+        debug("Config {s} = {s}", .{ key, value });
+        if (mem.eql(u8, key, "base_branch")) {
+            options.base_branch = try configString(allocator, key, value);
+        } else if (mem.eql(u8, key, "regexp")) {
+            options.regexp = try configString(allocator, key, value);
+        } else if (mem.eql(u8, key, "invert_match")) {
+            options.invert_match = try configString(allocator, key, value);
+        } else if (mem.eql(u8, key, "exclude")) {
+            for (try configStrings(allocator, key, value)) |name| {
+                try options.excludes.append(allocator, name);
+            }
+        } else if (mem.eql(u8, key, "filter")) {
+            options.filter = try configString(allocator, key, value);
+        } else if (mem.eql(u8, key, "include")) {
+            for (try configStrings(allocator, key, value)) |path| {
+                try options.includes.put(try std.fs.path.resolve(allocator, &[_][]const u8{ root, path }), {});
+            }
+        } else if (mem.eql(u8, key, "skip_dirs")) {
+            for (try configStrings(allocator, key, value)) |dir_name| {
+                try options.skip_dirs.append(allocator, dir_name);
+            }
+        } else if (mem.eql(u8, key, "name_rules")) {
+            options.name_rules = .{};
+            for (try configStrings(allocator, key, value)) |rule| {
+                try appendNameRule(allocator, options, rule);
+            }
+        } else if (mem.eql(u8, key, "gradle_cmd")) {
+            options.gradle_cmd = try configString(allocator, key, value);
+        } else if (mem.eql(u8, key, "threshold")) {
+            options.threshold = configInt(key, value);
+        } else if (mem.eql(u8, key, "jobs")) {
+            options.jobs = @max(1, configInt(key, value));
+        } else if (mem.eql(u8, key, "max_depth")) {
+            options.max_depth = parseMaxDepth(value);
+        } else if (mem.eql(u8, key, "per_gradle_root")) {
+            options.per_gradle_root = configBool(key, value);
+        } else if (mem.eql(u8, key, "with_dependency_projects")) {
+            options.include_local_dependencies = configBool(key, value);
+        } else {
+            warn("Unknown key {s} in {s}", .{ key, config_file_name });
+        }
+    }
+}
+
+fn stripConfigComment(line: []const u8) []const u8 {
+    var quote: ?u8 = null;
+    for (line, 0..) |c, i| {
+        if (quote) |q| {
+            if (c == q) quote = null;
+        } else if (c == '"' or c == '\'') {
+            quote = c;
+        } else if (c == '#') {
+            return line[0..i];
+        }
+    }
+    return line;
+}
+
+fn configString(allocator: Allocator, key: []const u8, value: []const u8) ![:0]const u8 {
+    if (value.len < 2 or value[0] != value[value.len - 1] or (value[0] != '"' and value[0] != '\'')) {
+        fatalWith(.usage, "Expect a string for {s} in {s}, got {s}", .{ key, config_file_name, value });
+    }
+    return allocator.dupeZ(u8, value[1 .. value.len - 1]);
+}
+
+fn configStrings(allocator: Allocator, key: []const u8, value: []const u8) ![]const [:0]const u8 {
+    if (value.len < 2 or value[0] != '[' or value[value.len - 1] != ']') {
+        fatalWith(.usage, "Expect an array of strings for {s} in {s}, got {s}", .{ key, config_file_name, value });
+    }
+    var values = ArrayList([:0]const u8).init(allocator);
+    const items = value[1 .. value.len - 1];
+    // a comma in a string like "a{1,2}" doesn't end the item
+    var quote: ?u8 = null;
+    var start = @as(usize, 0);
+    for (items, 0..) |c, i| {
+        if (quote) |q| {
+            if (c == q) quote = null;
+        } else if (c == '"' or c == '\'') {
+            quote = c;
+        }
+        if (quote != null or (c != ',' and i + 1 < items.len)) continue;
+        const end = if (c == ',') i else i + 1;
+        const trimmed = mem.trim(u8, items[start..end], " \t");
+        if (trimmed.len > 0) {
+            try values.append(try configString(allocator, key, trimmed));
+        }
+        start = i + 1;
+    }
+    if (quote != null) {
+        fatalWith(.usage, "Expect an array of strings for {s} in {s}, got {s}", .{ key, config_file_name, value });
+    }
+    return try values.toOwnedSlice();
+}
+
+fn configInt(key: []const u8, value: []const u8) usize {
+    return std.fmt.parseInt(usize, value, 10) catch fatalWith(.usage, "Expect an integer for {s} in {s}, got {s}", .{ key, config_file_name, value });
+}
+
+fn configBool(key: []const u8, value: []const u8) bool {
+    if (mem.eql(u8, value, "true")) return true;
+    if (mem.eql(u8, value, "false")) return false;
+    fatalWith(.usage, "Expect true or false for {s} in {s}, got {s}", .{ key, config_file_name, value });
+}
+
+const SettingsDsl = enum {
+    Kotlin,
+    Groovy,
+
+    /// Kotlin DSL unless the directory only has a Groovy `settings.gradle`.
+    fn detect(dir: fs.Dir) SettingsDsl {
+        dir.access("settings.gradle.kts", .{}) catch {
+            dir.access("settings.gradle", .{}) catch return .Kotlin;
+            return .Groovy;
+        };
+        return .Kotlin;
+    }
+
+    fn fromFileName(file_name: []const u8) SettingsDsl {
+        return if (mem.endsWith(u8, file_name, ".gradle")) .Groovy else .Kotlin;
+    }
+};
+
 fn write(allocator: Allocator, projects: []Projects.Entry, settings_file: []const u8) !void {
     const cwd = std.fs.cwd();
     const dir = if (std.fs.path.dirname(settings_file)) |dir| try std.fs.cwd().openDir(dir, .{}) else cwd;
@@ -483,44 +2130,81 @@ fn write(allocator: Allocator, projects: []Projects.Entry, settings_file: []cons
         fatal("Can create file {s} {}ex", .{ settings_file, ex });
     };
     defer file.close();
-    _ = try file.writeAll(
+    const dsl = SettingsDsl.fromFileName(settings_file);
+    _ = try file.writeAll(switch (dsl) {
+        .Kotlin =>
         \\// this is auto generated, please don't edit.
         \\// You can add logic in settings.pre.gradle.kts instead.
         \\// Ue `abt` can regenerate this file.
         \\
-        \\
-    );
-    _ = try file.writeAll(
         \\val pre = "settings.pre.gradle.kts"
         \\if (file(pre).exists()) apply(pre)
         \\
         \\
-    );
+        ,
+        .Groovy =>
+        \\// this is auto generated, please don't edit.
+        \\// You can add logic in settings.pre.gradle instead.
+        \\// Ue `abt` can regenerate this file.
+        \\
+        \\def pre = "settings.pre.gradle"
+        \\if (file(pre).exists()) apply from: pre
+        \\
+        \\
+        ,
+    });
 
     debug("Start writing projects into {s}", .{settings_file});
     var relative_paths = StringHashMap([]const u8).init(allocator);
     const dir_path = try dir.realpathAlloc(allocator, ".");
     for (projects) |p| {
+        if (p.name.len == 0) {
+            debug("The root project at {s} is included by gradle", .{p.root});
+            continue;
+        }
         info("Add project {s} to {s}", .{ p.name, settings_file });
         const relative = try relative_paths.getOrPut(p.root);
         if (!relative.found_existing) {
-            relative.value_ptr.* = try std.fs.path.relative(allocator, dir_path, p.root);
-            if (relative.value_ptr.*.len == 0) {
-                relative.value_ptr.* = ".";
-            }
+            relative.value_ptr.* = try relativeRoot(allocator, dir_path, p.root);
         }
-        const text = try std.fmt.allocPrint(allocator,
-            \\include(":{s}")
-            \\project(":{s}").projectDir = file("{s}/{s}")
-            \\
-            \\
-        , .{ p.name, p.name, relative.value_ptr.*, p.path });
+        const project_dir = try gradlePath(allocator, &[_][]const u8{ relative.value_ptr.*, p.path }, std.fs.path.sep);
+        const text = switch (dsl) {
+            .Kotlin => try std.fmt.allocPrint(allocator,
+                \\include(":{s}")
+                \\project(":{s}").projectDir = file("{s}")
+                \\
+                \\
+            , .{ p.name, p.name, project_dir }),
+            .Groovy => try std.fmt.allocPrint(allocator,
+                \\include ':{s}'
+                \\project(':{s}').projectDir = new File(settingsDir, '{s}')
+                \\
+                \\
+            , .{ p.name, p.name, project_dir }),
+        };
         defer allocator.free(text);
 
         _ = try file.writeAll(text);
     }
 }
 
+/// The root of projects as seen from the settings file dir, which share no common ancestor
+/// on Windows when they are on different drives, then the absolute root is used.
+fn relativeRoot(allocator: Allocator, settings_dir: []const u8, root: []const u8) ![]const u8 {
+    const relative = try std.fs.path.relative(allocator, settings_dir, root);
+    return if (relative.len == 0) "." else relative;
+}
+
+/// Joins the native paths with `/`, which gradle accepts on every platform, backslashes
+/// would be taken as escapes in the settings file.
+fn gradlePath(allocator: Allocator, paths: []const []const u8, native_sep: u8) ![]const u8 {
+    const joined = try mem.join(allocator, "/", paths);
+    if (native_sep != '/') {
+        mem.replaceScalar(u8, joined, native_sep, '/');
+    }
+    return joined;
+}
+
 fn exec(allocator: Allocator, cmd: []const []const u8, cwd: ?[]const u8) ![]const u8 {
     info("Execute external command: {s} in {s}", .{ cmd, cwd orelse "." });
     const result = try std.process.Child.run(.{
@@ -540,6 +2224,34 @@ fn exec(allocator: Allocator, cmd: []const []const u8, cwd: ?[]const u8) ![]cons
     return result.stdout;
 }
 
+/// Runs the batches on up to `jobs` threads, no new batch is started once one fails unless `keep_going`.
+fn runConcurrently(batches: []Batch, total: usize, jobs: usize, runner: *Runner) !void {
+    var next = std.atomic.Value(usize).init(0);
+    var failed = std.atomic.Value(bool).init(false);
+    var workers: [max_jobs]std.Thread = undefined;
+    const count = @min(jobs, batches.len, max_jobs);
+    for (workers[0..count]) |*worker| {
+        worker.* = try std.Thread.spawn(.{}, runBatches, .{ batches, total, runner, &next, &failed });
+    }
+    for (workers[0..count]) |worker| {
+        worker.join();
+    }
+}
+
+fn runBatches(batches: []Batch, total: usize, runner: *Runner, next: *std.atomic.Value(usize), failed: *std.atomic.Value(bool)) void {
+    while (!failed.load(.acquire)) {
+        const index = next.fetchAdd(1, .monotonic);
+        if (index >= batches.len) return;
+        const batch = &batches[index];
+        info("Execute {}:{}/{} {s}", .{ batch.first + 1, batch.first + batch.projects.len, total, batch.command });
+        // the arena of main isn't thread safe
+        batch.run(std.heap.page_allocator, runner);
+        if (!batch.succeeded() and !runner.keep_going) {
+            failed.store(true, .release);
+        }
+    }
+}
+
 fn spawn(allocator: Allocator, cmd: []const []const u8, cwd: ?[]const u8) !std.process.Child.Term {
     var child = std.process.Child.init(cmd, allocator);
     if (cwd) |dir| {
@@ -549,20 +2261,447 @@ fn spawn(allocator: Allocator, cmd: []const []const u8, cwd: ?[]const u8) !std.p
     child.stdout_behavior = .Inherit;
     child.stderr_behavior = .Inherit;
 
+    // the progress is cleared and not drawn over what the command prints meanwhile
+    std.debug.lockStdErr();
+    defer std.debug.unlockStdErr();
     return child.spawnAndWait();
 }
 
-fn fatal(comptime format: []const u8, args: anytype) noreturn {
-    std.log.err(format, args);
-    process.exit(1);
+fn writeJunit(path: []const u8, batches: []const Batch) !void {
+    const file = try std.fs.cwd().createFile(path, .{ .truncate = true });
+    defer file.close();
+    var buffered = io.bufferedWriter(file.writer());
+    try junitReport(buffered.writer(), batches);
+    try buffered.flush();
+    info("Junit report written to {s}", .{path});
 }
 
-test "test regex common patterns" {
-    const pattern = "foo|bar";
-
-    var arena = std.heap.ArenaAllocator.init(std.heap.c_allocator);
-    defer arena.deinit();
-    const allocator = arena.allocator();
+/// A project fails with its batch, and is skipped if its batch didn't run after an earlier failure.
+fn junitReport(writer: anytype, batches: []const Batch) !void {
+    var tests = @as(usize, 0);
+    var failures = @as(usize, 0);
+    var skipped = @as(usize, 0);
+    for (batches) |batch| {
+        tests += batch.projects.len;
+        if (!batch.ran()) {
+            skipped += batch.projects.len;
+        } else if (!batch.succeeded()) {
+            failures += batch.projects.len;
+        }
+    }
+    try writer.print(
+        \\<?xml version="1.0" encoding="UTF-8"?>
+        \\<testsuite name="abt" tests="{}" failures="{}" skipped="{}">
+        \\
+    , .{ tests, failures, skipped });
+    for (batches) |batch| {
+        for (batch.projects) |p| {
+            try writer.writeAll("  <testcase classname=\"abt\" name=\"");
+            try writeXmlEscaped(writer, p.name);
+            if (!batch.ran()) {
+                try writer.writeAll("\">\n    <skipped/>\n  </testcase>\n");
+            } else if (batch.err) |e| {
+                try writer.print("\">\n    <failure message=\"{s}\"/>\n  </testcase>\n", .{@errorName(e)});
+            } else if (!batch.succeeded()) {
+                try writer.writeAll("\">\n    <failure message=\"");
+                try writeTerm(writer, batch.term.?);
+                try writer.writeAll("\"/>\n  </testcase>\n");
+            } else {
+                try writer.writeAll("\"/>\n");
+            }
+        }
+    }
+    try writer.writeAll("</testsuite>\n");
+}
+
+/// How long each phase of a run takes, printed by --timings.
+const Timings = struct {
+    phases: [std.meta.fields(Phase).len]u64 = [_]u64{0} ** std.meta.fields(Phase).len,
+
+    const Phase = enum { scan, select, dependencies, settings, gradle };
+
+    fn start() i128 {
+        return std.time.nanoTimestamp();
+    }
+
+    fn record(self: *Timings, phase: Phase, started: i128) void {
+        self.phases[@intFromEnum(phase)] += @intCast(@max(0, std.time.nanoTimestamp() - started));
+    }
+
+    fn print(self: Timings) void {
+        std.debug.lockStdErr();
+        defer std.debug.unlockStdErr();
+        self.write(io.getStdErr().writer()) catch {};
+    }
+
+    fn write(self: Timings, writer: anytype) !void {
+        var total = @as(u64, 0);
+        try writer.writeAll("PHASE         TIME\n");
+        for (self.phases, 0..) |ns, i| {
+            const name = @tagName(@as(Phase, @enumFromInt(i)));
+            try writer.print("{s}", .{name});
+            try writer.writeByteNTimes(' ', 14 - name.len);
+            try writer.print("{}\n", .{std.fmt.fmtDuration(ns)});
+            total += ns;
+        }
+        try writer.print("total         {}\n", .{std.fmt.fmtDuration(total)});
+    }
+};
+
+fn elapsedMillis(started: i64) u64 {
+    return @intCast(@max(0, std.time.milliTimestamp() - started));
+}
+
+/// What abt built and how it went, for CI to archive and compare.
+const Summary = struct {
+    compare_commit: ?[]const u8,
+    projects: []const []const u8,
+    succeeded: bool,
+    duration_ms: u64,
+    runs: []const Run,
+
+    const Run = struct {
+        command: []const []const u8,
+        projects: []const []const u8,
+        status: []const u8,
+        exit_code: ?u8,
+        duration_ms: u64,
+    };
+};
+
+fn writeSummary(allocator: Allocator, path: []const u8, compare_commit: ?[]const u8, batches: []const Batch, duration_ms: u64) !void {
+    const file = try std.fs.cwd().createFile(path, .{ .truncate = true });
+    defer file.close();
+    var buffered = io.bufferedWriter(file.writer());
+    try summaryReport(allocator, buffered.writer(), compare_commit, batches, duration_ms);
+    try buffered.flush();
+    info("Summary written to {s}", .{path});
+}
+
+fn summaryReport(allocator: Allocator, writer: anytype, compare_commit: ?[]const u8, batches: []const Batch, duration_ms: u64) !void {
+    var projects = ArrayList([]const u8).init(allocator);
+    const runs = try allocator.alloc(Summary.Run, batches.len);
+    var succeeded = true;
+    for (batches, runs) |batch, *run| {
+        const names = try allocator.alloc([]const u8, batch.projects.len);
+        for (batch.projects, names) |p, *name| {
+            name.* = p.name;
+        }
+        try projects.appendSlice(names);
+        var outcome = ArrayList(u8).init(allocator);
+        if (!batch.ran()) {
+            try outcome.appendSlice("skipped");
+        } else if (batch.err) |e| {
+            try outcome.appendSlice(@errorName(e));
+        } else if (batch.succeeded()) {
+            try outcome.appendSlice("succeeded");
+        } else {
+            try writeTerm(outcome.writer(), batch.term.?);
+        }
+        succeeded = succeeded and batch.succeeded();
+        run.* = .{
+            .command = batch.command,
+            .projects = names,
+            .status = try outcome.toOwnedSlice(),
+            .exit_code = if (batch.term) |term| switch (term) {
+                .Exited => |code| code,
+                else => null,
+            } else null,
+            .duration_ms = batch.duration_ms,
+        };
+    }
+    try std.json.stringify(Summary{
+        .compare_commit = compare_commit,
+        .projects = projects.items,
+        .succeeded = succeeded,
+        .duration_ms = duration_ms,
+        .runs = runs,
+    }, .{}, writer);
+    try writer.writeByte('\n');
+}
+
+fn writeTerm(writer: anytype, term: std.process.Child.Term) !void {
+    switch (term) {
+        .Exited => |code| try writer.print("exited with {}", .{code}),
+        .Signal => |signal| try writer.print("killed by signal {}", .{signal}),
+        .Stopped => |signal| try writer.print("stopped by signal {}", .{signal}),
+        .Unknown => |status| try writer.print("unknown status {}", .{status}),
+    }
+}
+
+fn writeXmlEscaped(writer: anytype, text: []const u8) !void {
+    for (text) |c| {
+        switch (c) {
+            '&' => try writer.writeAll("&amp;"),
+            '<' => try writer.writeAll("&lt;"),
+            '>' => try writer.writeAll("&gt;"),
+            '"' => try writer.writeAll("&quot;"),
+            else => try writer.writeByte(c),
+        }
+    }
+}
+
+/// Runs the gradle commands, shared by all the batches.
+const Runner = struct {
+    timeout: ?u64 = null,
+    log: ?*Log = null,
+    github_actions: bool = false,
+    group_output: bool = false,
+    keep_going: bool = false,
+
+    /// Unlike `spawn`, the command is terminated once it runs longer than `timeout` seconds,
+    /// its output is copied into the log file if any, and the end of its stderr is kept in `tail`.
+    /// Stderr is left to a terminal when there is no log file, gradle keeps its rich console then
+    /// and the error is already on the screen, so nothing is kept in `tail`.
+    fn run(self: *Runner, allocator: Allocator, cmd: []const []const u8, cwd: ?[]const u8, tail: *Tail) !std.process.Child.Term {
+        var child = std.process.Child.init(cmd, allocator);
+        child.cwd = cwd;
+        child.stdin_behavior = .Ignore;
+        child.stdout_behavior = if (self.log == null) .Inherit else .Pipe;
+        const pipe_stderr = self.log != null or !io.getStdErr().isTty();
+        child.stderr_behavior = if (pipe_stderr) .Pipe else .Inherit;
+        // a GRADLE_CMD script may not exec gradle, the whole group is stopped then
+        if (builtin.os.tag != .windows and self.timeout != null) {
+            child.pgid = 0;
+        }
+        try child.spawn();
+
+        var watchdog = Watchdog{ .group = child.id, .cmd = cmd };
+        var watcher: ?std.Thread = null;
+        const slot = if (self.timeout != null) watchdog.register() else null;
+        defer if (slot) |group| group.store(0, .release);
+        if (self.timeout) |timeout| {
+            watcher = std.Thread.spawn(.{}, Watchdog.watch, .{ &watchdog, timeout }) catch |e| blk: {
+                warn("Can't watch the timeout of {s}: {}", .{ cmd, e });
+                break :blk null;
+            };
+        }
+        // gradle prints nothing while configuring, the output is piped so it can't draw its own progress
+        var spinner = Spinner{};
+        if (self.log != null and log_level != .err and io.getStdErr().isTty()) spinner.start();
+        defer spinner.stop();
+        var copiers: [2]?std.Thread = .{ null, null };
+        if (self.log) |log| {
+            copiers[0] = std.Thread.spawn(.{}, copyOutput, .{ child.stdout.?, io.getStdOut(), log, @as(?*Tail, null), &spinner }) catch |e| blk: {
+                warn("Can't copy the output of {s}: {}", .{ cmd, e });
+                break :blk null;
+            };
+        }
+        if (pipe_stderr) {
+            copiers[1] = std.Thread.spawn(.{}, copyOutput, .{ child.stderr.?, io.getStdErr(), self.log, tail, &spinner }) catch |e| blk: {
+                warn("Can't copy the output of {s}: {}", .{ cmd, e });
+                break :blk null;
+            };
+        }
+        // the pipes are read until every process holding them exits, then waiting the child closes them
+        for (copiers) |copier| {
+            if (copier) |thread| thread.join();
+        }
+        const term = child.wait();
+        watchdog.done.set();
+        if (watcher) |thread| thread.join();
+        if (watchdog.fired) return error.Timeout;
+        return term;
+    }
+};
+
+/// Stops the process group of a command running longer than the timeout, gradlew execs the gradle
+/// client, stopping it makes the daemon cancel the build too.
+const Watchdog = struct {
+    group: std.process.Child.Id,
+    cmd: []const []const u8,
+    done: std.Thread.ResetEvent = .{},
+    fired: bool = false,
+
+    fn watch(self: *Watchdog, timeout: u64) void {
+        self.done.timedWait(timeout * std.time.ns_per_s) catch {
+            warn("{s} is still running after {}s, terminate it", .{ self.cmd, timeout });
+            self.fired = true;
+            self.signal(std.posix.SIG.TERM);
+            self.done.timedWait(kill_grace_seconds * std.time.ns_per_s) catch {
+                warn("{s} doesn't stop after {}s, kill it", .{ self.cmd, kill_grace_seconds });
+                self.signal(std.posix.SIG.KILL);
+            };
+        };
+    }
+
+    fn signal(self: *Watchdog, sig: u8) void {
+        std.posix.kill(-self.group, sig) catch |e| warn("Can't stop {s}: {}", .{ self.cmd, e });
+    }
+
+    /// Keeps the group in a free slot of `running_groups` until the returned slot is reset.
+    fn register(self: *Watchdog) ?*std.atomic.Value(std.posix.pid_t) {
+        for (&running_groups) |*slot| {
+            if (slot.cmpxchgStrong(0, self.group, .acq_rel, .monotonic) == null) return slot;
+        }
+        return null;
+    }
+};
+
+/// The gradle process groups started with a timeout, they miss the Ctrl-C of the terminal, which only
+/// reaches its foreground group, so it is passed on to them.
+var running_groups = [_]std.atomic.Value(std.posix.pid_t){std.atomic.Value(std.posix.pid_t).init(0)} ** max_jobs;
+
+fn passSignal(sig: i32) callconv(.C) void {
+    for (&running_groups) |*slot| {
+        const group = slot.load(.acquire);
+        if (group != 0) std.posix.kill(-group, @intCast(sig)) catch {};
+    }
+    // then abt stops as if gradle were in its group
+    const default = std.posix.Sigaction{
+        .handler = .{ .handler = std.posix.SIG.DFL },
+        .mask = std.posix.empty_sigset,
+        .flags = 0,
+    };
+    std.posix.sigaction(@intCast(sig), &default, null) catch {};
+    std.posix.raise(@intCast(sig)) catch {};
+}
+
+fn passInterrupts() !void {
+    const action = std.posix.Sigaction{
+        .handler = .{ .handler = passSignal },
+        .mask = std.posix.empty_sigset,
+        .flags = 0,
+    };
+    try std.posix.sigaction(std.posix.SIG.INT, &action, null);
+    try std.posix.sigaction(std.posix.SIG.TERM, &action, null);
+}
+
+/// The file the gradle output is copied into, concurrent batches write it in turn.
+const Log = struct {
+    file: std.fs.File,
+    mutex: std.Thread.Mutex = .{},
+
+    fn write(self: *Log, bytes: []const u8) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        self.file.writeAll(bytes) catch |e| warn("Can't write log file: {}", .{e});
+    }
+};
+
+fn copyOutput(from: std.fs.File, to: std.fs.File, log: ?*Log, tail: ?*Tail, spinner: *Spinner) void {
+    var buf: [4096]u8 = undefined;
+    while (true) {
+        const n = from.read(&buf) catch |e| {
+            warn("Can't read the gradle output: {}", .{e});
+            return;
+        };
+        if (n == 0) return;
+        spinner.stop();
+        to.writeAll(buf[0..n]) catch {};
+        if (log) |l| l.write(buf[0..n]);
+        if (tail) |t| t.write(buf[0..n]);
+    }
+}
+
+/// Shows gradle is running until it prints anything, the spinner is cleared before its first output.
+const Spinner = struct {
+    done: std.Thread.ResetEvent = .{},
+    thread: ?std.Thread = null,
+    mutex: std.Thread.Mutex = .{},
+
+    const frames = [_]u8{ '|', '/', '-', '\\' };
+
+    fn start(self: *Spinner) void {
+        // concurrent batches would draw over each other
+        if (spinning.swap(true, .acq_rel)) return;
+        self.thread = std.Thread.spawn(.{}, draw, .{self}) catch |e| {
+            warn("Can't show the gradle spinner: {}", .{e});
+            spinning.store(false, .release);
+            return;
+        };
+    }
+
+    /// Called before every output is copied, only the first call clears the spinner.
+    fn stop(self: *Spinner) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        const thread = self.thread orelse return;
+        self.thread = null;
+        self.done.set();
+        thread.join();
+        spinning.store(false, .release);
+    }
+
+    fn draw(self: *Spinner) void {
+        const stderr = io.getStdErr().writer();
+        var frame = @as(usize, 0);
+        while (true) : (frame += 1) {
+            {
+                std.debug.lockStdErr();
+                defer std.debug.unlockStdErr();
+                stderr.print("\r{c} Wait for gradle to print", .{frames[frame % frames.len]}) catch {};
+            }
+            self.done.timedWait(100 * std.time.ns_per_ms) catch continue;
+            break;
+        }
+        std.debug.lockStdErr();
+        defer std.debug.unlockStdErr();
+        stderr.writeAll("\r\x1b[K") catch {};
+    }
+};
+var spinning = std.atomic.Value(bool).init(false);
+
+const tail_lines = 50;
+const tail_bytes = 16 * 1024;
+
+/// The last bytes written into it, enough for `tail_lines` lines of the gradle stderr in most cases.
+const Tail = struct {
+    buf: [tail_bytes]u8 = undefined,
+    len: usize = 0,
+
+    fn write(self: *Tail, bytes: []const u8) void {
+        if (bytes.len >= self.buf.len) {
+            @memcpy(&self.buf, bytes[bytes.len - self.buf.len ..]);
+            self.len = self.buf.len;
+            return;
+        }
+        const keep = @min(self.len, self.buf.len - bytes.len);
+        mem.copyForwards(u8, self.buf[0..keep], self.buf[self.len - keep .. self.len]);
+        @memcpy(self.buf[keep .. keep + bytes.len], bytes);
+        self.len = keep + bytes.len;
+    }
+
+    /// The last `tail_lines` lines kept, without the trailing line breaks.
+    fn lines(self: *const Tail) []const u8 {
+        const text = mem.trimRight(u8, self.buf[0..self.len], "\n");
+        var end = text.len;
+        var count = @as(usize, 0);
+        while (mem.lastIndexOfScalar(u8, text[0..end], '\n')) |i| {
+            count += 1;
+            if (count == tail_lines) return text[i + 1 ..];
+            end = i;
+        }
+        return text;
+    }
+};
+
+const kill_grace_seconds = 10;
+
+/// The exit status tells CI the kind of the failure, e.g. a failed git call may pass in a retry but a failed gradle run won't.
+const ExitCode = enum(u8) {
+    failure = 1,
+    usage = 2,
+    build = 3,
+    git = 4,
+    empty = 5,
+};
+
+fn fatal(comptime format: []const u8, args: anytype) noreturn {
+    fatalWith(.failure, format, args);
+}
+
+fn fatalWith(code: ExitCode, comptime format: []const u8, args: anytype) noreturn {
+    std.log.err(format, args);
+    process.exit(@intFromEnum(code));
+}
+
+test "test regex common patterns" {
+    const pattern = "foo|bar";
+
+    var arena = std.heap.ArenaAllocator.init(std.heap.c_allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
     const re = @cImport(@cInclude("regez.h"));
     var buf = try allocator.alloc(u8, 512);
     const buf_ptr: [*c]u8 = @ptrCast(buf.ptr);
@@ -581,3 +2720,771 @@ test "test regex common patterns" {
     mem.copyForwards(u8, buf, "foba");
     std.debug.assert(re.isMatch(buf_ptr) == 1);
 }
+
+test "scan descends at most max depth levels" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    try tmp.dir.makePath("a/b/c/d");
+    (try tmp.dir.createFile("a/b/c/d/build.gradle.kts", .{})).close();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    var shallow = Projects.init(allocator);
+    try shallow.scan(root, 3);
+    try std.testing.expectEqual(@as(usize, 0), shallow.entries[@intFromEnum(Projects.State.Added)].items.len);
+
+    var deep = Projects.init(allocator);
+    try deep.scan(root, 4);
+    const found = deep.entries[@intFromEnum(Projects.State.Added)].items;
+    try std.testing.expectEqual(@as(usize, 1), found.len);
+    try std.testing.expectEqualStrings("a:b:c:d", found[0].name);
+    try std.testing.expectEqualStrings("a/b/c/d", found[0].path);
+}
+
+test "batch settings files keep the gradle extension" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+
+    try std.testing.expectEqualStrings("build.settings.2.gradle.kts", try batchSettingsFile(allocator, "build.settings.gradle.kts", 2));
+    try std.testing.expectEqualStrings("out/build.settings.1.gradle", try batchSettingsFile(allocator, "out/build.settings.gradle", 1));
+    try std.testing.expectEqualStrings("settings.3", try batchSettingsFile(allocator, "settings", 3));
+}
+
+test "config file gives option defaults" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    var options = Options{
+        .includes = StringHashMap(void).init(allocator),
+        .commands = std.ArrayList([]const u8).init(allocator),
+    };
+
+    try applyConfig(allocator,
+        \\# defaults of the team
+        \\base_branch = "origin/develop"
+        \\invert_match = "legacy$" # not built any more
+        \\include = [ "../shared", 'libs' ]
+        \\exclude = [ "legacy,old", 'tools' ]
+        \\gradle_cmd = "./gradlew --offline"
+        \\max_depth = 4
+        \\with_dependency_projects = true
+        \\
+    , "/repo/app", &options);
+
+    try std.testing.expectEqualStrings("origin/develop", options.base_branch.?);
+    try std.testing.expectEqualStrings("legacy$", options.invert_match.?);
+    try std.testing.expect(options.includes.contains("/repo/shared"));
+    try std.testing.expect(options.includes.contains("/repo/app/libs"));
+    try std.testing.expectEqual(@as(usize, 2), options.excludes.items.len);
+    try std.testing.expectEqualStrings("legacy,old", options.excludes.items[0]);
+    try std.testing.expectEqualStrings("tools", options.excludes.items[1]);
+    try std.testing.expectEqualStrings("./gradlew --offline", options.gradle_cmd.?);
+    try std.testing.expectEqual(@as(usize, 4), options.max_depth);
+    try std.testing.expect(options.include_local_dependencies);
+}
+
+test "parse both project notations of dependencies" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const names = try parseDependencies(arena.allocator(),
+        \\dependencies {
+        \\    implementation(project(":core:data"))
+        \\    implementation(projects.featureFoo)
+        \\    api(projects.feature.fooBar)
+        \\    // implementation(projects.legacy)
+        \\    subprojects.forEach { }
+        \\}
+    );
+
+    const expected = [_][]const u8{ "core:data", "projects.featureFoo", "projects.feature.fooBar" };
+    try std.testing.expectEqual(expected.len, names.len);
+    for (expected, names) |e, n| {
+        try std.testing.expectEqualStrings(e, n);
+    }
+}
+
+test "parse dependencies wrapped into many lines" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const names = try parseDependencies(arena.allocator(),
+        \\dependencies {
+        \\    implementation(
+        \\        project(
+        \\            ":foo"
+        \\        )
+        \\    )
+        \\    // implementation(project(":commented"))
+        \\    /* implementation(
+        \\        project(":blocked")) */
+        \\    implementation("com.example:lib:1.0") // project(":trailing")
+        \\    api(project(':bar'))
+        \\}
+    );
+
+    const expected = [_][]const u8{ "foo", "bar" };
+    try std.testing.expectEqual(expected.len, names.len);
+    for (expected, names) |e, n| {
+        try std.testing.expectEqualStrings(e, n);
+    }
+}
+
+test "junit report fails and skips projects with their batch" {
+    var projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "core", .path = "core", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "legacy", .path = "legacy", .root = "/repo", .is_build_file_kts = false },
+    };
+    const command = [_][]const u8{ "./gradlew", "build" };
+    const batches = [_]Batch{
+        .{ .first = 0, .projects = projects[0..1], .settings_file = "s", .command = &command, .term = .{ .Exited = 0 } },
+        .{ .first = 1, .projects = projects[1..2], .settings_file = "s", .command = &command, .term = .{ .Exited = 1 } },
+        .{ .first = 2, .projects = projects[2..3], .settings_file = "s", .command = &command },
+    };
+    var report = ArrayList(u8).init(std.testing.allocator);
+    defer report.deinit();
+    try junitReport(report.writer(), &batches);
+
+    try std.testing.expectEqualStrings(
+        \\<?xml version="1.0" encoding="UTF-8"?>
+        \\<testsuite name="abt" tests="3" failures="1" skipped="1">
+        \\  <testcase classname="abt" name="app"/>
+        \\  <testcase classname="abt" name="core">
+        \\    <failure message="exited with 1"/>
+        \\  </testcase>
+        \\  <testcase classname="abt" name="legacy">
+        \\    <skipped/>
+        \\  </testcase>
+        \\</testsuite>
+        \\
+    , report.items);
+}
+
+test "relative root of projects" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+
+    try std.testing.expectEqualStrings(".", try relativeRoot(allocator, "/repo", "/repo"));
+    try std.testing.expectEqualStrings("..", try relativeRoot(allocator, "/repo/app", "/repo"));
+    try std.testing.expectEqualStrings("../lib", try relativeRoot(allocator, "/repo/app", "/repo/lib"));
+    try std.testing.expectEqualStrings("../../shared/libs", try relativeRoot(allocator, "/work/repo", "/shared/libs"));
+    try std.testing.expectEqualStrings("lib", try relativeRoot(allocator, "/repo", "/repo/./lib/"));
+}
+
+test "gradle paths use forward slashes" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+
+    try std.testing.expectEqualStrings("../lib/feature/foo", try gradlePath(allocator, &[_][]const u8{ "..\\lib", "feature\\foo" }, '\\'));
+    try std.testing.expectEqualStrings("./app/core", try gradlePath(allocator, &[_][]const u8{ ".", "app/core" }, '/'));
+}
+
+test "scan skips build outputs" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    try tmp.dir.makePath("app/build/generated");
+    try tmp.dir.makePath("vendor/lib");
+    (try tmp.dir.createFile("app/build.gradle.kts", .{})).close();
+    (try tmp.dir.createFile("app/build/generated/build.gradle", .{})).close();
+    (try tmp.dir.createFile("vendor/lib/build.gradle", .{})).close();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    var projects = Projects.init(allocator);
+    projects.skip_dirs = &[_][]const u8{ "build", "vendor" };
+    try projects.scan(root, 3);
+    const found = projects.entries[@intFromEnum(Projects.State.Added)].items;
+    try std.testing.expectEqual(@as(usize, 1), found.len);
+    try std.testing.expectEqualStrings("app", found[0].name);
+}
+
+test "project names are rewritten by name rules" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    var projects = Projects.init(arena.allocator());
+
+    try std.testing.expectEqualStrings("feature:foo-android", try projects.projectName(&[_][]const u8{ "feature", ":", "foo", ":", "android" }));
+    try std.testing.expectEqualStrings("domain", try projects.projectName(&[_][]const u8{"domain"}));
+
+    const rules = [_]NameRule{NameRule.parse(":impl=")};
+    projects.name_rules = &rules;
+    try std.testing.expectEqualStrings("feature:foo:android", try projects.projectName(&[_][]const u8{ "feature", ":", "foo", ":", "android" }));
+    try std.testing.expectEqualStrings("feature:foo", try projects.projectName(&[_][]const u8{ "feature", ":", "foo", ":", "impl" }));
+}
+
+test "explain why projects are picked" {
+    const projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true, .reason = .{ .changed = "abc123" } },
+        .{ .name = "core:data", .path = "core/data", .root = "/repo", .is_build_file_kts = true, .reason = .{ .dependency = "app" } },
+        .{ .name = "lib", .path = "lib", .root = "/repo", .is_build_file_kts = true, .reason = .{ .regexp = "^lib" } },
+        .{ .name = "tools", .path = "tools", .root = "/repo", .is_build_file_kts = false },
+    };
+    var table = ArrayList(u8).init(std.testing.allocator);
+    defer table.deinit();
+    try explain(table.writer(), &projects);
+
+    try std.testing.expectEqualStrings(
+        \\PROJECT    REASON
+        \\app        changed since abc123
+        \\core:data  dependency of app
+        \\lib        name matches ^lib
+        \\tools      no regexp given
+        \\
+    , table.items);
+}
+
+test "only one spinner is drawn at a time" {
+    var first = Spinner{};
+    var second = Spinner{};
+    first.start();
+    second.start();
+    try std.testing.expect(first.thread != null);
+    try std.testing.expect(second.thread == null);
+    first.stop();
+    first.stop();
+    try std.testing.expect(!spinning.load(.acquire));
+    second.start();
+    try std.testing.expect(second.thread != null);
+    second.stop();
+}
+
+test "tail keeps the last lines of the output" {
+    var tail = Tail{};
+    try std.testing.expectEqualStrings("", tail.lines());
+
+    tail.write("> Task :app:compileKotlin FAILED\n");
+    tail.write("e: Unresolved reference: foo\n\n");
+    try std.testing.expectEqualStrings("> Task :app:compileKotlin FAILED\ne: Unresolved reference: foo", tail.lines());
+
+    for (0..tail_lines) |_| {
+        tail.write("line\n");
+    }
+    tail.write("BUILD FAILED\n");
+    const lines = tail.lines();
+    try std.testing.expectEqual(@as(usize, tail_lines - 1), mem.count(u8, lines, "\n"));
+    try std.testing.expect(mem.startsWith(u8, lines, "line\n"));
+    try std.testing.expect(mem.endsWith(u8, lines, "\nBUILD FAILED"));
+
+    const long = [_]u8{'x'} ** (tail_bytes + 10);
+    tail.write(&long);
+    try std.testing.expectEqual(@as(usize, tail_bytes), tail.lines().len);
+}
+
+test "gradle args go between the settings file and the tasks" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const command = try gradleCommand(arena.allocator(), &[_][]const u8{ "./gradlew", "--no-daemon" }, "build.settings.gradle.kts", &[_][]const u8{ "--offline", "-Pci=true" }, &[_][]const u8{ "assemble", "test" });
+
+    const expected = [_][]const u8{ "./gradlew", "--no-daemon", "-c", "build.settings.gradle.kts", "--offline", "-Pci=true", "assemble", "test" };
+    try std.testing.expectEqual(expected.len, command.len);
+    for (expected, command) |e, c| {
+        try std.testing.expectEqualStrings(e, c);
+    }
+}
+
+test "group projects by their gradle roots" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    try tmp.dir.makePath("mobile/app");
+    try tmp.dir.makePath("mobile/core/data");
+    try tmp.dir.makePath("backend/api");
+    (try tmp.dir.createFile("mobile/gradlew", .{})).close();
+    (try tmp.dir.createFile("backend/gradlew", .{})).close();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    var projects = [_]Projects.Entry{
+        .{ .name = "mobile:app", .path = "mobile/app", .root = root, .is_build_file_kts = true },
+        .{ .name = "backend:api", .path = "backend/api", .root = root, .is_build_file_kts = true },
+        .{ .name = "mobile:core:data", .path = "mobile/core/data", .root = root, .is_build_file_kts = true },
+    };
+    const roots = try groupByGradleRoot(allocator, &projects);
+
+    try std.testing.expectEqual(@as(usize, 2), roots.len);
+    try std.testing.expectEqualStrings(try std.fs.path.join(allocator, &[_][]const u8{ root, "mobile" }), roots[0].dir.?);
+    try std.testing.expectEqual(@as(usize, 0), roots[0].first);
+    try std.testing.expectEqual(@as(usize, 2), roots[0].projects.len);
+    try std.testing.expectEqualStrings("mobile:app", roots[0].projects[0].name);
+    try std.testing.expectEqualStrings("mobile:core:data", roots[0].projects[1].name);
+    try std.testing.expectEqualStrings(try std.fs.path.join(allocator, &[_][]const u8{ root, "backend" }), roots[1].dir.?);
+    try std.testing.expectEqual(@as(usize, 2), roots[1].first);
+    try std.testing.expectEqualStrings("backend:api", roots[1].projects[0].name);
+}
+
+test "status of the selection" {
+    const projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true },
+    };
+    var out = ArrayList(u8).init(std.testing.allocator);
+    defer out.deinit();
+    try writeStatus(out.writer(), "feature/foo", null, "abc123", &[_][]const u8{ "app/src/Main.kt", "README.md" }, &projects);
+
+    try std.testing.expectEqualStrings(
+        \\Branch: feature/foo
+        \\Upstream: none
+        \\Compare commit: abc123
+        \\Changed files: 2
+        \\  app/src/Main.kt
+        \\  README.md
+        \\Selected projects: 1
+        \\  app
+        \\
+    , out.items);
+}
+
+test "scan roots concurrently in the order of the roots" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    var path_buf: [64]u8 = undefined;
+    for ([_][]const u8{ "a", "b", "c" }) |root| {
+        for (0..200) |i| {
+            const path = try std.fmt.bufPrint(&path_buf, "{s}/m{}/feature/build.gradle.kts", .{ root, i });
+            try tmp.dir.makePath(std.fs.path.dirname(path).?);
+            (try tmp.dir.createFile(path, .{})).close();
+        }
+    }
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const roots = [_][]const u8{
+        try tmp.dir.realpathAlloc(allocator, "a"),
+        try tmp.dir.realpathAlloc(allocator, "b"),
+        try tmp.dir.realpathAlloc(allocator, "c"),
+    };
+
+    var concurrent = Projects.init(allocator);
+    try concurrent.scanAll(&roots, 3);
+    var serial = Projects.init(allocator);
+    for (roots) |root| {
+        try serial.scan(root, 3);
+    }
+
+    const found = concurrent.entries[@intFromEnum(Projects.State.Added)].items;
+    const expected = serial.entries[@intFromEnum(Projects.State.Added)].items;
+    try std.testing.expectEqual(@as(usize, 600), found.len);
+    for (expected, found) |e, f| {
+        try std.testing.expectEqualStrings(e.root, f.root);
+        try std.testing.expectEqualStrings(e.name, f.name);
+    }
+}
+
+test "picked projects are sorted by name" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    for ([_][]const u8{ "lib/b", "app", "lib/a", "legacy", "core" }) |path| {
+        try tmp.dir.makePath(path);
+        var dir = try tmp.dir.openDir(path, .{});
+        defer dir.close();
+        (try dir.createFile("build.gradle.kts", .{})).close();
+    }
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    var runs: [2][]const Projects.Entry = undefined;
+    for (&runs) |*picked| {
+        var projects = Projects.init(allocator);
+        try projects.scan(root, 3);
+        try projects.pickAll();
+        try projects.deny("^legacy$");
+        projects.sortPicked();
+        picked.* = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+    }
+
+    const expected = [_][]const u8{ "app", "core", "lib:a", "lib:b" };
+    for (runs) |picked| {
+        try std.testing.expectEqual(expected.len, picked.len);
+        for (expected, picked) |e, p| {
+            try std.testing.expectEqualStrings(e, p.name);
+        }
+    }
+}
+
+test "exclude projects by exact names" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    var projects = Projects.init(arena.allocator());
+    for ([_][:0]const u8{ "app", "app-legacy", "core", "lib.v2" }) |name| {
+        try projects.entries[@intFromEnum(Projects.State.Picked)].append(.{ .name = name, .path = name, .root = "/repo", .is_build_file_kts = true });
+    }
+
+    try projects.denyNames(&[_][]const u8{ "app-legacy", "lib.v2", "missing" });
+    projects.sortPicked();
+
+    const picked = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+    try std.testing.expectEqual(@as(usize, 2), picked.len);
+    try std.testing.expectEqualStrings("app", picked[0].name);
+    try std.testing.expectEqualStrings("core", picked[1].name);
+    try std.testing.expectEqual(@as(usize, 2), projects.entries[@intFromEnum(Projects.State.Denied)].items.len);
+}
+
+test "last selection is reused until it is stale" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const path = try std.fs.path.join(allocator, &[_][]const u8{ try tmp.dir.realpathAlloc(allocator, "."), LastSelection.file_name });
+    var options = Options{
+        .includes = StringHashMap(void).init(allocator),
+        .commands = std.ArrayList([]const u8).init(allocator),
+    };
+    try options.includes.put("/repo", {});
+    try options.includes.put("/shared", {});
+    options.regexp = "^app";
+    const key = selectionKey(&options);
+
+    const projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true, .reason = .{ .changed = "abc123" } },
+        .{ .name = "app-legacy", .path = "legacy", .root = "/repo", .is_build_file_kts = false, .reason = .{ .dependency = "app" } },
+        .{ .name = "app-shared", .path = "shared", .root = "/shared", .is_build_file_kts = true },
+    };
+    const changed_files = [_][]const u8{"app/src/main/kotlin/App.kt"};
+    try LastSelection.save(allocator, path, "abc123", key, .{ .compare_commit = "fff000", .changed_files = &changed_files, .entries = &projects });
+
+    const reused = (try LastSelection.load(allocator, path, "abc123", key)).?;
+    try std.testing.expectEqualStrings("fff000", reused.compare_commit.?);
+    try std.testing.expectEqual(changed_files.len, reused.changed_files.len);
+    try std.testing.expectEqualStrings(changed_files[0], reused.changed_files[0]);
+    try std.testing.expectEqual(projects.len, reused.entries.len);
+    for (projects, reused.entries) |p, r| {
+        try std.testing.expectEqualStrings(p.name, r.name);
+        try std.testing.expectEqualStrings(p.path, r.path);
+        try std.testing.expectEqualStrings(p.root, r.root);
+        try std.testing.expectEqual(p.is_build_file_kts, r.is_build_file_kts);
+        try std.testing.expectEqual(std.meta.activeTag(p.reason), std.meta.activeTag(r.reason));
+    }
+    try std.testing.expectEqualStrings("abc123", reused.entries[0].reason.changed);
+    try std.testing.expectEqualStrings("app", reused.entries[1].reason.dependency);
+    try std.testing.expect(try LastSelection.load(allocator, path, "def456", key) == null);
+
+    options.regexp = "^core";
+    try std.testing.expect(selectionKey(&options) != key);
+    try std.testing.expect(try LastSelection.load(allocator, path, "abc123", selectionKey(&options)) == null);
+}
+
+test "task of each project" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "core:data", .path = "core/data", .root = "/repo", .is_build_file_kts = true },
+    };
+    const tasks = try projectTasks(arena.allocator(), &projects, "ktlintCheck", &[_][]const u8{"--continue"});
+
+    const expected = [_][]const u8{ ":app:ktlintCheck", ":core:data:ktlintCheck", "--continue" };
+    try std.testing.expectEqual(expected.len, tasks.len);
+    for (expected, tasks) |e, t| {
+        try std.testing.expectEqualStrings(e, t);
+    }
+}
+
+test "wrapper is made executable" {
+    if (builtin.os.tag == .windows) return error.SkipZigTest;
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    (try tmp.dir.createFile("gradlew", .{ .mode = 0o644 })).close();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const dir = try tmp.dir.realpathAlloc(arena.allocator(), ".");
+    try checkCommand(arena.allocator(), dir, wrapper_command[0]);
+
+    const stat = try tmp.dir.statFile("gradlew");
+    try std.testing.expectEqual(@as(std.fs.File.Mode, 0o755), stat.mode & 0o777);
+}
+
+test "expand environment variables in the gradle command" {
+    var env = std.process.EnvMap.init(std.testing.allocator);
+    defer env.deinit();
+    try env.put("HOME", "/home/me");
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+
+    try std.testing.expectEqualStrings(
+        "/home/me/gradle/bin/gradle -Dinit=/home/me/init.gradle  $ 5$ ${HOME",
+        try expandEnv(arena.allocator(), "$HOME/gradle/bin/gradle -Dinit=${HOME}/init.gradle $UNKNOWN $ 5$ ${HOME", &env),
+    );
+}
+
+test "summary of the gradle runs" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    var projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "core", .path = "core", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "legacy", .path = "legacy", .root = "/repo", .is_build_file_kts = false },
+    };
+    const command = [_][]const u8{ "./gradlew", "build" };
+    const batches = [_]Batch{
+        .{ .first = 0, .projects = projects[0..1], .settings_file = "s", .command = &command, .term = .{ .Exited = 0 }, .duration_ms = 1000 },
+        .{ .first = 1, .projects = projects[1..2], .settings_file = "s", .command = &command, .term = .{ .Exited = 1 }, .duration_ms = 500 },
+        .{ .first = 2, .projects = projects[2..3], .settings_file = "s", .command = &command },
+    };
+    var report = ArrayList(u8).init(std.testing.allocator);
+    defer report.deinit();
+    try summaryReport(arena.allocator(), report.writer(), "abc123", &batches, 1600);
+
+    try std.testing.expectEqualStrings(
+        \\{"compare_commit":"abc123","projects":["app","core","legacy"],"succeeded":false,"duration_ms":1600,"runs":[
+    ++
+        \\{"command":["./gradlew","build"],"projects":["app"],"status":"succeeded","exit_code":0,"duration_ms":1000},
+    ++
+        \\{"command":["./gradlew","build"],"projects":["core"],"status":"exited with 1","exit_code":1,"duration_ms":500},
+    ++
+        \\{"command":["./gradlew","build"],"projects":["legacy"],"status":"skipped","exit_code":null,"duration_ms":0}]}
+        \\
+    , report.items);
+}
+
+test "timings table" {
+    var timings = Timings{};
+    timings.phases = .{ 12 * std.time.ns_per_ms, 3 * std.time.ns_per_ms, 0, 1500 * std.time.ns_per_us, 61 * std.time.ns_per_s };
+    var table = ArrayList(u8).init(std.testing.allocator);
+    defer table.deinit();
+    try timings.write(table.writer());
+
+    try std.testing.expectEqualStrings(
+        \\PHASE         TIME
+        \\scan          12ms
+        \\select        3ms
+        \\dependencies  0ns
+        \\settings      1.5ms
+        \\gradle        1m1s
+        \\total         1m1.016s
+        \\
+    , table.items);
+}
+
+test "projects file forces projects to be picked" {
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const lines = try parseProjectsFile(allocator,
+        \\# always checked
+        \\security
+        \\
+        \\  lib.v2  # exact name
+        \\^audit:
+    );
+    try std.testing.expectEqual(@as(usize, 3), lines.len);
+    try std.testing.expectEqualStrings("security", lines[0]);
+    try std.testing.expectEqualStrings("lib.v2", lines[1]);
+    try std.testing.expectEqualStrings("^audit:", lines[2]);
+
+    var projects = Projects.init(allocator);
+    for ([_][:0]const u8{ "security", "security-android", "libxv2", "audit:api" }) |name| {
+        try projects.entries[@intFromEnum(Projects.State.Denied)].append(.{ .name = name, .path = name, .root = "/repo", .is_build_file_kts = true });
+    }
+    try projects.entries[@intFromEnum(Projects.State.Added)].append(.{ .name = "lib.v2", .path = "lib", .root = "/repo", .is_build_file_kts = true });
+    try projects.force(lines);
+    projects.sortPicked();
+
+    const picked = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+    const expected = [_][]const u8{ "audit:api", "lib.v2", "security" };
+    try std.testing.expectEqual(expected.len, picked.len);
+    for (expected, picked) |e, p| {
+        try std.testing.expectEqualStrings(e, p.name);
+        try std.testing.expect(p.reason == .forced);
+    }
+}
+
+test "renamed, deleted and added files change their projects" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    for ([_][]const u8{ "app", "core", "lib", "other", "quiet" }) |path| {
+        try tmp.dir.makePath(path);
+        var dir = try tmp.dir.openDir(path, .{});
+        defer dir.close();
+        (try dir.createFile("build.gradle.kts", .{})).close();
+    }
+    (try tmp.dir.createFile("core/gone.txt", .{})).close();
+    (try tmp.dir.createFile("lib/moved.txt", .{})).close();
+    const git = [_][]const u8{ "git", "-c", "init.defaultBranch=main", "-c", "user.name=abt", "-c", "user.email=abt@example.com" };
+    _ = exec(allocator, &(git ++ [_][]const u8{ "init", "-q" }), root) catch return error.SkipZigTest;
+    _ = try exec(allocator, &(git ++ [_][]const u8{ "add", "." }), root);
+    _ = try exec(allocator, &(git ++ [_][]const u8{ "commit", "-q", "-m", "init" }), root);
+    _ = try exec(allocator, &(git ++ [_][]const u8{ "mv", "lib/moved.txt", "other/moved.txt" }), root);
+    _ = try exec(allocator, &(git ++ [_][]const u8{ "rm", "-q", "core/gone.txt" }), root);
+    (try tmp.dir.createFile("app/added.txt", .{})).close();
+
+    const targets = [_]DiffTarget{ .workdir, .index, .head };
+    const expected = [_][]const []const u8{
+        &[_][]const u8{ "app", "core", "lib", "other" },
+        &[_][]const u8{ "core", "lib", "other" },
+        &[_][]const u8{},
+    };
+    for (targets, expected) |target, names| {
+        var projects = Projects.init(allocator);
+        try projects.scan(root, 3);
+        try projects.pickAll();
+        try projects.denyUnchanged(root, "HEAD", max_depth_allowed, target);
+        projects.sortPicked();
+
+        const picked = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+        try std.testing.expectEqual(names.len, picked.len);
+        for (names, picked) |e, p| {
+            try std.testing.expectEqualStrings(e, p.name);
+        }
+    }
+}
+
+test "batches keep the projects depending on each other" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const build_files = [_][2][]const u8{
+        .{ "app", "dependencies { implementation(project(\":core\")) }" },
+        .{ "lib", "" },
+        .{ "core", "" },
+        .{ "tool", "" },
+    };
+    var path_buf: [64]u8 = undefined;
+    for (build_files) |build_file| {
+        try tmp.dir.makePath(build_file[0]);
+        const file = try tmp.dir.createFile(try std.fmt.bufPrint(&path_buf, "{s}/build.gradle.kts", .{build_file[0]}), .{});
+        defer file.close();
+        try file.writeAll(build_file[1]);
+    }
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+    var projects: [build_files.len]Projects.Entry = undefined;
+    for (build_files, &projects) |build_file, *p| {
+        p.* = .{ .name = try allocator.dupeZ(u8, build_file[0]), .path = build_file[0], .root = root, .is_build_file_kts = true };
+    }
+
+    const batches = try splitBatches(allocator, &projects, 2, null);
+    try std.testing.expectEqual(@as(usize, 2), batches.len);
+    try std.testing.expectEqualStrings("app", batches[0][0].name);
+    try std.testing.expectEqualStrings("core", batches[0][1].name);
+    try std.testing.expectEqualStrings("lib", batches[1][0].name);
+    try std.testing.expectEqualStrings("tool", batches[1][1].name);
+    try std.testing.expectEqual(@as(usize, 1), (try splitBatches(allocator, &projects, 4, null)).len);
+}
+
+test "dependency cache of another version is dropped" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const path = try std.fs.path.join(allocator, &[_][]const u8{ try tmp.dir.realpathAlloc(allocator, "."), DependencyCache.file_name });
+
+    var cache = try DependencyCache.load(allocator, path);
+    try cache.put("/repo/app/build.gradle.kts", 42, &[_][]const u8{"core"});
+    cache.save();
+    var loaded = try DependencyCache.load(allocator, path);
+    try std.testing.expectEqualStrings("core", loaded.get("/repo/app/build.gradle.kts", 42).?[0]);
+
+    const old = try tmp.dir.createFile(DependencyCache.file_name, .{ .truncate = true });
+    try old.writeAll("[{\"file\":\"/repo/app/build.gradle.kts\",\"mtime\":42,\"dependencies\":[\"core\"]}]");
+    old.close();
+    var dropped = try DependencyCache.load(allocator, path);
+    try std.testing.expect(dropped.get("/repo/app/build.gradle.kts", 42) == null);
+    try std.testing.expectError(error.FileNotFound, tmp.dir.access(DependencyCache.file_name, .{}));
+}
+
+test "quiet is found among the options only" {
+    var given = mem.tokenizeScalar(u8, "-C repo -e app --quiet build", ' ');
+    try std.testing.expect(quietGiven(&given));
+    var value = mem.tokenizeScalar(u8, "-e -q build", ' ');
+    try std.testing.expect(!quietGiven(&value));
+    var gradle = mem.tokenizeScalar(u8, "-d build -q", ' ');
+    try std.testing.expect(!quietGiven(&gradle));
+    var separated = mem.tokenizeScalar(u8, "-- -q", ' ');
+    try std.testing.expect(!quietGiven(&separated));
+}
+
+test "settings file of a nested gradle root names projects relative to it" {
+    var tmp = std.testing.tmpDir(.{});
+    defer tmp.cleanup();
+    const build_files = [_][2][]const u8{
+        .{ "mobile", "" },
+        .{ "mobile/app", "dependencies { implementation(projects.core) }" },
+        .{ "mobile/core", "" },
+        .{ "backend/core", "" },
+    };
+    var path_buf: [64]u8 = undefined;
+    for (build_files) |build_file| {
+        try tmp.dir.makePath(build_file[0]);
+        const file = try tmp.dir.createFile(try std.fmt.bufPrint(&path_buf, "{s}/build.gradle.kts", .{build_file[0]}), .{});
+        defer file.close();
+        try file.writeAll(build_file[1]);
+    }
+    (try tmp.dir.createFile("mobile/gradlew", .{})).close();
+    (try tmp.dir.createFile("backend/gradlew", .{})).close();
+
+    var arena = std.heap.ArenaAllocator.init(std.testing.allocator);
+    defer arena.deinit();
+    const allocator = arena.allocator();
+    const root = try tmp.dir.realpathAlloc(allocator, ".");
+
+    var projects = Projects.init(allocator);
+    try projects.scan(root, 3);
+    try projects.rebaseOnGradleRoots();
+    try projects.pick("^app$");
+    try projects.add_local_dependencies();
+    projects.sortPicked();
+    const picked = projects.entries[@intFromEnum(Projects.State.Picked)].items;
+    try std.testing.expectEqual(@as(usize, 2), picked.len);
+    try std.testing.expectEqualStrings("app", picked[0].name);
+    try std.testing.expectEqualStrings("core", picked[1].name);
+    const mobile = try std.fs.path.join(allocator, &[_][]const u8{ root, "mobile" });
+    try std.testing.expectEqualStrings(mobile, picked[1].root);
+
+    const options = Options{
+        .includes = StringHashMap(void).init(allocator),
+        .commands = std.ArrayList([]const u8).init(allocator),
+    };
+    const roots = try groupByGradleRoot(allocator, picked);
+    try std.testing.expectEqual(@as(usize, 1), roots.len);
+    const settings_file = try roots[0].settingsFile(allocator, &options);
+    try std.testing.expectEqualStrings(try std.fs.path.join(allocator, &[_][]const u8{ mobile, "settings.gradle.kts" }), settings_file);
+    try write(allocator, roots[0].projects, settings_file);
+
+    const content = try tmp.dir.readFileAlloc(allocator, "mobile/settings.gradle.kts", 1024 * 1024);
+    try std.testing.expect(mem.indexOf(u8, content, "include(\":app\")") != null);
+    try std.testing.expect(mem.indexOf(u8, content, "include(\":core\")") != null);
+    try std.testing.expect(mem.indexOf(u8, content, "project(\":core\").projectDir = file(\"./core\")") != null);
+    try std.testing.expect(mem.indexOf(u8, content, ":mobile") == null);
+}
+
+test "accessors match the project names gradle makes them from" {
+    for ([_][]const u8{ "feature:foo-bar", "feature:foo_bar", "feature:fooBar" }) |name| {
+        try std.testing.expect(isDependency(name, "projects.feature.fooBar"));
+    }
+    try std.testing.expect(!isDependency("feature:foo", "projects.feature.fooBar"));
+    try std.testing.expect(!isDependency("feature:foo-bar-baz", "projects.feature.fooBar"));
+    try std.testing.expect(isDependency("core", "core"));
+    try std.testing.expect(!isDependency("core", "projects.core.data"));
+
+    const projects = [_]Projects.Entry{
+        .{ .name = "app", .path = "app", .root = "/repo", .is_build_file_kts = true },
+        .{ .name = "feature_foo", .path = "feature_foo", .root = "/repo", .is_build_file_kts = true },
+    };
+    try std.testing.expectEqual(@as(?usize, 1), dependencyIndex(&projects, "projects.featureFoo"));
+    try std.testing.expectEqual(@as(?usize, null), dependencyIndex(&projects, "projects.featureBar"));
+}